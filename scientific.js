@@ -0,0 +1,126 @@
+// Hover previews for references to figures, tables, equations, theorems and citations
+//
+// A reference links to the anchor of its target. On hover the target is looked up in the current
+// page or fetched from its chapter, and a copy of it is shown in a popup.
+(function () {
+    "use strict";
+
+    var SELECTOR = "a.fig_ref, a.tab_ref, a.equ_ref, a.thm_ref, a.bib_ref";
+    var DELAY = 300;
+
+    // parsed chapters by their URL, pending fetches share one promise
+    var pages = {};
+    var popup = null;
+    var timer = null;
+
+    function fetchPage(url) {
+        if (!pages[url]) {
+            pages[url] = fetch(url)
+                .then(function (res) { return res.ok ? res.text() : Promise.reject(res.status); })
+                .then(function (text) { return new DOMParser().parseFromString(text, "text/html"); });
+        }
+
+        return pages[url];
+    }
+
+    // make relative URLs of fragments and images point to the chapter they were copied from
+    function rebase(node, base) {
+        node.querySelectorAll("object[data], img[src], a[href]").forEach(function (elm) {
+            var attr = elm.tagName === "OBJECT" ? "data" : elm.tagName === "IMG" ? "src" : "href";
+            var val = elm.getAttribute(attr);
+            if (!val.startsWith("data:")) {
+                elm.setAttribute(attr, new URL(val, base).href);
+            }
+        });
+    }
+
+    // the element shown for an anchor, the anchors of labelled rows are empty spans inside of the
+    // equation and bibliography entries are split into a term and its description
+    function preview(doc, id) {
+        var target = doc.getElementById(id);
+        if (!target) {
+            return null;
+        }
+
+        var out = document.createElement("div");
+        if (target.tagName === "SPAN" && !target.textContent && target.parentElement) {
+            out.appendChild(target.parentElement.cloneNode(true));
+        } else if (target.closest("dt")) {
+            var term = target.closest("dt");
+            out.appendChild(term.cloneNode(true));
+            if (term.nextElementSibling && term.nextElementSibling.tagName === "DD") {
+                out.appendChild(term.nextElementSibling.cloneNode(true));
+            }
+        } else {
+            out.appendChild(target.cloneNode(true));
+        }
+
+        // ids of the copy would collide with the page
+        out.querySelectorAll("[id]").forEach(function (elm) { elm.removeAttribute("id"); });
+
+        return out;
+    }
+
+    function hide() {
+        clearTimeout(timer);
+        if (popup) {
+            popup.remove();
+            popup = null;
+        }
+    }
+
+    function show(link) {
+        var url = new URL(link.getAttribute("href"), document.baseURI);
+        var id = decodeURIComponent(url.hash.slice(1));
+        if (!id) {
+            return;
+        }
+
+        var page = url.href.split("#")[0];
+        var here = document.baseURI.split("#")[0];
+        var doc = page === here ? Promise.resolve(document) : fetchPage(page);
+
+        doc.then(function (doc) {
+            var content = preview(doc, id);
+            if (!content || !link.matches(":hover")) {
+                return;
+            }
+
+            rebase(content, page);
+            hide();
+
+            popup = document.createElement("div");
+            popup.className = "reference_preview";
+            popup.appendChild(content);
+            document.body.appendChild(popup);
+
+            // place the popup below the link, or above it at the bottom of the window
+            var rect = link.getBoundingClientRect();
+            var left = Math.min(rect.left, window.innerWidth - popup.offsetWidth - 10);
+            var top = rect.bottom + 5;
+            if (top + popup.offsetHeight > window.innerHeight) {
+                top = rect.top - popup.offsetHeight - 5;
+            }
+
+            popup.style.left = Math.max(left, 10) + window.scrollX + "px";
+            popup.style.top = Math.max(top, 10) + window.scrollY + "px";
+        }).catch(function () {
+            // without a preview the link still works, for example for books opened from disk
+        });
+    }
+
+    document.addEventListener("mouseover", function (event) {
+        var link = event.target.closest && event.target.closest(SELECTOR);
+        if (link) {
+            clearTimeout(timer);
+            timer = setTimeout(function () { show(link); }, DELAY);
+        }
+    });
+
+    document.addEventListener("mouseout", function (event) {
+        var link = event.target.closest && event.target.closest(SELECTOR);
+        if (link && !link.contains(event.relatedTarget)) {
+            hide();
+        }
+    });
+})();
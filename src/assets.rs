@@ -0,0 +1,102 @@
+use std::fs;
+use std::path::Path;
+
+use mdbook::utils::fs::path_to_root;
+
+use crate::cache::Cache;
+use crate::error::{Error, Result};
+use crate::references::chapter_url;
+
+const BASE64_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encode bytes with the standard base64 alphabet and padding
+fn base64(input: &[u8]) -> String {
+    let mut out = String::with_capacity(input.len().div_ceil(3) * 4);
+
+    for chunk in input.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let val = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_CHARS[(val >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+/// Where rendered fragments are published for the renderer
+pub enum Assets {
+    /// Copy fragments into this subdirectory of the book source, from where the renderer copies
+    /// them into the build output
+    Src(String),
+    /// Embed fragments as data URLs into the generated markup, no files are written
+    Inline,
+}
+
+impl Assets {
+    /// Parse the `assets_mode` and `assets_dir` configuration
+    pub fn from_config(mode: Option<&str>, dir: Option<&str>) -> Result<Assets> {
+        match mode {
+            Some("src") | None => Ok(Assets::Src(dir.unwrap_or("assets").trim_matches('/').to_string())),
+            Some("inline") => Ok(Assets::Inline),
+            Some(x) => Err(Error::InvalidConfig(format!("unknown assets mode `{}`, expected `src` or `inline`", x))),
+        }
+    }
+
+    /// URL under which a fragment is referenced in the markup of the given chapter
+    pub fn url(&self, cache: &Cache, chapter: &Path, file: &str) -> Result<String> {
+        match self {
+            Assets::Src(dir) => Ok(format!("{}{}/{}", path_to_root(chapter), dir, file)),
+            Assets::Inline => {
+                let content = fs::read(cache.path().join(file)).map_err(Error::Io)?;
+
+                Ok(format!("data:image/svg+xml;base64,{}", base64(&content)))
+            }
+        }
+    }
+
+    /// URL of an anchor in a chapter, relative to the published fragments
+    ///
+    /// Fragments embedded as data URLs have no location, so links can't be resolved from them.
+    pub fn link(&self, chapter: &Path, anchor: &str) -> Option<String> {
+        let url = match anchor {
+            "" => chapter_url(chapter),
+            anchor => format!("{}#{}", chapter_url(chapter), anchor),
+        };
+
+        match self {
+            Assets::Src(dir) => Some(format!("{}{}", "../".repeat(dir.split('/').count()), url)),
+            Assets::Inline => None,
+        }
+    }
+
+    /// Copy the used fragments into the source directory, if required by the mode
+    ///
+    /// Files which are already up to date are not touched to avoid triggering the file watcher of
+    /// `mdbook serve`.
+    pub fn publish(&self, cache: &Cache, src: &Path, used_fragments: &[String]) -> Result<()> {
+        let dest = match self {
+            Assets::Src(dir) => src.join(dir),
+            Assets::Inline => return Ok(()),
+        };
+
+        if !dest.exists() {
+            fs::create_dir_all(&dest).map_err(Error::Io)?;
+        }
+
+        for fragment in used_fragments {
+            let content = fs::read(cache.path().join(fragment)).map_err(Error::Io)?;
+
+            if fs::read(dest.join(fragment)).ok().as_ref() != Some(&content) {
+                fs::write(dest.join(fragment), content).map_err(Error::Io)?;
+            }
+        }
+
+        Ok(())
+    }
+}
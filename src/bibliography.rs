@@ -0,0 +1,705 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use nom_bibtex::Bibtex;
+
+use crate::csl::Csl;
+use crate::error::{Error, Result};
+use crate::locale::Locale;
+
+/// Style of the labels and entries, named like the corresponding BibTeX styles or given by a CSL file
+#[derive(Debug)]
+pub enum Style {
+    /// Numbered entries with full names, sorted by author
+    Plain,
+    /// Like `Plain`, but with abbreviated first names
+    Abbrv,
+    /// Labels from the authors and the year like `Knu84`, sorted by label
+    Alpha,
+    /// Labels with the last names and the year like `Knuth, 1984`, sorted by author
+    AuthorYear,
+    /// Citations and entries formatted by a Citation Style Language file
+    Csl(Box<Csl>),
+}
+
+impl Style {
+    /// Parse the `bibliography_style` option, the default is `plain`
+    ///
+    /// A path ending in `.csl` loads a style in the Citation Style Language.
+    pub fn from_config(cfg: Option<&toml::Value>, locale: &Locale) -> Result<Style> {
+        match cfg.map(|x| x.as_str()) {
+            None | Some(Some("plain")) => Ok(Style::Plain),
+            Some(Some("abbrv")) => Ok(Style::Abbrv),
+            Some(Some("alpha")) => Ok(Style::Alpha),
+            Some(Some("author-year")) => Ok(Style::AuthorYear),
+            Some(Some(path)) if path.ends_with(".csl") => {
+                if !Path::new(path).exists() {
+                    return Err(Error::InvalidConfig(format!("citation style {:?} not found", path)));
+                }
+
+                Csl::from_file(Path::new(path), locale).map(|x| Style::Csl(Box::new(x)))
+            },
+            _ => Err(Error::InvalidConfig("`bibliography_style` has to be `plain`, `abbrv`, `alpha`, `author-year` or the path of a `.csl` file".into())),
+        }
+    }
+}
+
+/// Order of the entries in the bibliography
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Order {
+    /// Order given by the style, a CSL style without sort keys orders by citation
+    Style,
+    /// Order of the first citation in the book
+    Citation,
+    /// By author, year and title
+    Author,
+    /// By year, author and title
+    Year,
+}
+
+impl Order {
+    /// Parse the `bibliography_order` option, the default is the order of the style
+    pub fn from_config(cfg: Option<&toml::Value>) -> Result<Order> {
+        match cfg.map(|x| x.as_str()) {
+            None | Some(Some("style")) => Ok(Order::Style),
+            Some(Some("citation")) => Ok(Order::Citation),
+            Some(Some("author")) => Ok(Order::Author),
+            Some(Some("year")) => Ok(Order::Year),
+            _ => Err(Error::InvalidConfig("`bibliography_order` has to be `style`, `citation`, `author` or `year`".into())),
+        }
+    }
+}
+
+/// Name of a person with the last name including particles like `van`
+#[derive(Clone, Debug)]
+pub struct Name {
+    pub first: String,
+    pub last: String,
+}
+
+/// List of names, which may end with `and others`
+#[derive(Clone, Debug, Default)]
+pub struct Names {
+    pub list: Vec<Name>,
+    pub others: bool,
+}
+
+impl Names {
+    fn parse(value: Option<&String>) -> Names {
+        let mut list = value.map(|x| parse_names(x)).unwrap_or_default();
+        let others = list.last().map(|x| x.last == "others" && x.first.is_empty()).unwrap_or(false);
+        if others {
+            list.pop();
+        }
+
+        Names { list, others }
+    }
+}
+
+impl Name {
+    /// First names abbreviated to their initials, like `D. E.`
+    pub fn initials(&self, separator: &str) -> String {
+        self.first.split(|x: char| x.is_whitespace() || x == '-')
+            .filter_map(|x| x.chars().find(|x| x.is_alphabetic()))
+            .map(|x| format!("{}{}", x, separator))
+            .collect::<String>()
+            .trim_end()
+            .to_string()
+    }
+}
+
+/// Entry of the bibliography with its label
+pub struct Entry {
+    /// Citation key of the entry
+    pub key: String,
+    /// Label displayed in citations and in the bibliography
+    pub label: String,
+    /// Position in the bibliography, starting at one
+    pub number: usize,
+    /// Label before letters are appended to tell equal labels apart
+    stem: String,
+    /// Entry type in lowercase, like `article`
+    kind: String,
+    /// Fields with LaTeX markup replaced by text
+    fields: HashMap<String, String>,
+    authors: Names,
+    editors: Names,
+}
+
+impl Entry {
+    /// Field of the entry, with names of fields in lowercase
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(|x| x.as_str()).filter(|x| !x.is_empty())
+    }
+
+    /// Entry type in lowercase, like `article`
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    /// Authors or editors of the entry
+    pub fn names(&self, role: &str) -> &Names {
+        match role {
+            "editor" => &self.editors,
+            _ => &self.authors,
+        }
+    }
+
+    /// Authors, or editors if the entry has no authors, for example for proceedings
+    fn creators(&self) -> &Names {
+        if self.authors.list.is_empty() { &self.editors } else { &self.authors }
+    }
+
+    /// Year of publication, also taken from a `date` field
+    pub fn year(&self) -> &str {
+        self.field("year")
+            .or_else(|| self.field("date").map(|x| x.split('-').next().unwrap()))
+            .unwrap_or("")
+    }
+
+    /// Key for sorting by author, year and title
+    fn sort_key(&self) -> String {
+        let authors = self.creators().list.iter().map(|x| format!("{} {}", x.last, x.first)).collect::<Vec<_>>().join(" ");
+
+        format!("{}\u{0}{}\u{0}{}", authors, self.year(), self.field("title").unwrap_or("")).to_lowercase()
+    }
+}
+
+/// Bibliography loaded from a BibTeX file, with labels assigned by its style
+pub struct Bibliography {
+    style: Style,
+    entries: Vec<Entry>,
+}
+
+impl Bibliography {
+    /// Read a BibTeX file, then sort and label its entries
+    pub fn from_file(path: &Path, style: Style, locale: &Locale) -> Result<Bibliography> {
+        let bibtex = fs::read_to_string(path).map_err(Error::Io)?;
+        let bibtex = Bibtex::parse(&bibtex)
+            .map_err(|err| Error::InvalidBibliography(format!("could not parse {}: {}", path.display(), err)))?;
+
+        let mut entries = bibtex.bibliographies().iter().map(|entry| {
+            let fields = entry.tags().iter()
+                .map(|(key, val)| (key.to_lowercase(), latex_to_text(val)))
+                .collect::<HashMap<_, _>>();
+
+            Entry {
+                key: entry.citation_key().to_string(),
+                label: String::new(),
+                number: 0,
+                stem: String::new(),
+                kind: entry.entry_type().to_lowercase(),
+                fields,
+                authors: Names::parse(entry.tags().get("author")),
+                editors: Names::parse(entry.tags().get("editor")),
+            }
+        }).collect::<Vec<_>>();
+
+        match &style {
+            Style::Plain | Style::Abbrv => entries.sort_by_cached_key(|x| x.sort_key()),
+            Style::Alpha => {
+                for entry in &mut entries {
+                    entry.stem = alpha_label(entry);
+                }
+                entries.sort_by(|a, b| a.stem.to_lowercase().cmp(&b.stem.to_lowercase()).then_with(|| a.sort_key().cmp(&b.sort_key())));
+            },
+            Style::AuthorYear => {
+                for entry in &mut entries {
+                    entry.stem = format!("{}, {}", author_names(entry, locale), entry.year());
+                }
+                entries.sort_by_cached_key(|x| x.sort_key());
+            },
+            Style::Csl(csl) => csl.sort(&mut entries),
+        }
+
+        let mut bibliography = Bibliography { style, entries };
+        bibliography.label();
+
+        Ok(bibliography)
+    }
+
+    /// Number the entries in their current order and assign their labels
+    ///
+    /// Equal labels of the `alpha` and `author-year` styles get the letters `a`, `b`, .. in the
+    /// order of the bibliography.
+    fn label(&mut self) {
+        let mut stems = HashMap::new();
+        for entry in &self.entries {
+            *stems.entry(entry.stem.clone()).or_insert(0) += 1;
+        }
+
+        let mut seen = HashMap::new();
+        for (i, entry) in self.entries.iter_mut().enumerate() {
+            entry.number = i + 1;
+
+            entry.label = match &self.style {
+                Style::Plain | Style::Abbrv => entry.number.to_string(),
+                Style::Csl(csl) => csl.citation(entry),
+                Style::Alpha | Style::AuthorYear if stems[&entry.stem] > 1 => {
+                    let count = seen.entry(entry.stem.clone()).or_insert(0usize);
+                    *count += 1;
+                    format!("{}{}", entry.stem, (b'a' + ((*count - 1) % 26) as u8) as char)
+                },
+                Style::Alpha | Style::AuthorYear => entry.stem.clone(),
+            };
+        }
+    }
+
+    /// Select and order the entries shown in the bibliography and label them again
+    ///
+    /// With `only_cited` the bibliography only contains the cited entries and the entries listed in
+    /// `nocite`, where `*` includes all entries. In the order of citation, the entries which are
+    /// not cited follow in the order of the style.
+    pub fn arrange(&mut self, order: Order, cited: &[String], only_cited: bool, nocite: &[String]) -> Result<()> {
+        if let Some(key) = nocite.iter().find(|key| *key != "*" && !self.entries.iter().any(|x| x.key == **key)) {
+            return Err(Error::InvalidBibliography(format!("`{}` in `nocite` is not in the bibliography", key)));
+        }
+
+        if only_cited && !nocite.iter().any(|x| x == "*") {
+            self.entries.retain(|entry| cited.contains(&entry.key) || nocite.contains(&entry.key));
+        }
+
+        // like in CSL, styles without sort keys order the bibliography by citation
+        let order = match &self.style {
+            Style::Csl(csl) if order == Order::Style && !csl.sorted() => Order::Citation,
+            _ => order,
+        };
+
+        match order {
+            Order::Style => {},
+            Order::Citation => {
+                let position = |entry: &Entry| cited.iter().position(|x| *x == entry.key).unwrap_or(cited.len());
+                self.entries.sort_by_cached_key(position);
+            },
+            Order::Author => self.entries.sort_by_cached_key(|x| x.sort_key()),
+            Order::Year => self.entries.sort_by_cached_key(|x| format!("{}\u{0}{}", x.year(), x.sort_key())),
+        }
+
+        self.label();
+
+        Ok(())
+    }
+
+    /// Affixes around citations, like `(` and `)`, if they are given by the style
+    pub fn citation_affixes(&self) -> Option<(String, String)> {
+        match &self.style {
+            Style::Csl(csl) => Some(csl.citation_affixes()),
+            _ => None,
+        }
+    }
+
+    /// Delimiter between the entries of a citation, if it differs from the locale
+    pub fn citation_delimiter(&self) -> Option<String> {
+        match &self.style {
+            Style::Csl(csl) => csl.citation_delimiter(),
+            Style::AuthorYear => Some("; ".into()),
+            _ => None,
+        }
+    }
+
+    /// Short list of authors and year of an entry for author-year citations, like `Knuth et al.`
+    /// and `1984`
+    ///
+    /// The year has the letter of the label in the `author-year` style, like `1984a`.
+    pub fn author_year(&self, entry: &Entry, locale: &Locale) -> (String, String) {
+        let year = match self.style {
+            Style::AuthorYear => entry.label.rsplit(", ").next().unwrap_or("").to_string(),
+            _ => entry.year().to_string(),
+        };
+
+        (author_names(entry, locale), year)
+    }
+
+    /// Entries in the order of the bibliography
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Render the bibliography as HTML, the anchor of each entry is its citation key
+    pub fn to_html(&self, locale: &Locale) -> String {
+        let mut out = vec!["<dl class=\"bibliography\">".to_string()];
+
+        for entry in &self.entries {
+            // the entries of CSL styles contain their label
+            if let Style::Csl(csl) = &self.style {
+                out.push(format!("<dd id=\"{}\">{}</dd>", entry.key, csl.bibliography(entry)));
+                continue;
+            }
+
+            let body = format_entry(entry, &self.style, locale);
+
+            // author-year bibliographies are only sorted, without labels in front of the entries
+            if let Style::AuthorYear = self.style {
+                out.push(format!("<dd id=\"{}\">{}</dd>", entry.key, body));
+            } else {
+                out.push(format!("<dt id=\"{}\">{}</dt>", entry.key, locale.citation(&entry.label)));
+                out.push(format!("<dd>{}</dd>", body));
+            }
+        }
+
+        out.push("</dl>".into());
+        out.join("\n")
+    }
+}
+
+/// Label like `Knu84` for a single author, `KL94` for up to four and `ABC+99` for more
+pub fn alpha_label(entry: &Entry) -> String {
+    let abbreviation = |name: &str, len| name.split_whitespace().last().unwrap_or("")
+        .chars().filter(|x| x.is_alphabetic()).take(len).collect::<String>();
+
+    let names = entry.creators();
+    let mut label = match names.list.as_slice() {
+        [] => entry.key.chars().filter(|x| x.is_alphabetic()).take(3).collect(),
+        [author] if !names.others => abbreviation(&author.last, 3),
+        authors if authors.len() > 4 || names.others => {
+            let initials = authors.iter().take(3).map(|x| abbreviation(&x.last, 1)).collect::<String>();
+            format!("{}+", initials)
+        },
+        authors => authors.iter().map(|x| abbreviation(&x.last, 1)).collect(),
+    };
+
+    let year = entry.year();
+    label.extend(year.chars().skip(year.chars().count().saturating_sub(2)));
+
+    label
+}
+
+/// Last names for author-year labels, like `Knuth`, `Knuth and Lamport` or `Knuth et al.`
+fn author_names(entry: &Entry, locale: &Locale) -> String {
+    let et_al = locale.get("et_al").unwrap_or("et al.");
+
+    let names = entry.creators();
+    match names.list.as_slice() {
+        [] => entry.key.clone(),
+        [author] if names.others => format!("{} {}", author.last, et_al),
+        [author] => author.last.clone(),
+        [a, b] if !names.others => locale.enumeration(&[a.last.clone(), b.last.clone()]),
+        [author, ..] => format!("{} {}", author.last, et_al),
+    }
+}
+
+/// Render the authors of an entry in the form of the style
+fn format_authors(entry: &Entry, style: &Style, locale: &Locale) -> String {
+    let names = entry.creators();
+    let others = names.others;
+    let names = names.list.iter().map(|x| match style {
+        Style::Plain | Style::Alpha => format!("{} {}", x.first, x.last).trim().to_string(),
+        Style::Abbrv => format!("{} {}", x.initials(". "), x.last).trim().to_string(),
+        Style::AuthorYear if x.first.is_empty() => x.last.clone(),
+        Style::AuthorYear | Style::Csl(_) => format!("{}, {}", x.last, x.initials(". ")),
+    }).collect::<Vec<_>>();
+
+    if others {
+        format!("{} {}", names.join(", "), locale.get("et_al").unwrap_or("et al."))
+    } else {
+        locale.enumeration(&names)
+    }
+}
+
+/// Render the body of an entry, following the layout of the BibTeX styles
+fn format_entry(entry: &Entry, style: &Style, locale: &Locale) -> String {
+    let field = |name| entry.field(name).map(|x| x.to_string());
+    let emph = |name| entry.field(name).map(|x| format!("<em>{}</em>", x));
+    let join = |parts: Vec<Option<String>>| {
+        let parts = parts.into_iter().flatten().collect::<Vec<_>>();
+        if parts.is_empty() { None } else { Some(parts.join(", ")) }
+    };
+
+    let authors = Some(format_authors(entry, style, locale)).filter(|x| !x.is_empty());
+    let year = Some(entry.year().to_string()).filter(|x| !x.is_empty());
+    let pages = field("pages").map(|x| locale.fill(if x.contains('–') { "pages" } else { "page" }, &x));
+    let string = |key| locale.get(key).map(|x| x.to_string());
+
+    // the title of books is emphasized, the title of parts is not
+    let (title, source) = match entry.kind.as_str() {
+        "article" => {
+            let volume = match (field("volume"), field("number")) {
+                (Some(volume), Some(number)) => Some(format!("{}({})", volume, number)),
+                (volume, _) => volume,
+            };
+
+            (field("title"), join(vec![emph("journal"), volume, pages]))
+        },
+        "book" | "proceedings" => (emph("title"), join(vec![field("edition").map(|x| locale.fill("edition", &x)), field("publisher"), field("address")])),
+        "inproceedings" | "conference" | "incollection" | "inbook" => {
+            (field("title"), join(vec![emph("booktitle").map(|x| locale.fill("in", &x)), pages, field("publisher"), field("address")]))
+        },
+        "phdthesis" => (emph("title"), join(vec![string("phd_thesis"), field("school"), field("address")])),
+        "mastersthesis" => (emph("title"), join(vec![string("masters_thesis"), field("school"), field("address")])),
+        "techreport" => {
+            let report = match field("number") {
+                Some(number) => Some(locale.fill("report_number", &number)),
+                None => string("report"),
+            };
+
+            (field("title"), join(vec![report, field("institution"), field("address")]))
+        },
+        _ => (field("title"), join(vec![field("howpublished"), field("organization"), field("publisher")])),
+    };
+
+    let mut sentences = Vec::new();
+    match style {
+        Style::AuthorYear => {
+            let year = entry.label.rsplit(", ").next().map(|x| x.to_string()).or(year);
+            sentences.push(format!("{} ({})", authors.unwrap_or_default(), year.unwrap_or_default()).trim().to_string());
+            sentences.extend(title);
+            sentences.extend(source);
+        },
+        _ => {
+            sentences.extend(authors);
+            sentences.extend(title);
+            sentences.extend(join(vec![source, year]));
+        },
+    }
+    sentences.extend(field("note"));
+
+    let mut out = sentences.into_iter()
+        .map(|x| if x.ends_with(['.', '?', '!']) { x } else { format!("{}.", x) })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    if let Some(doi) = entry.field("doi") {
+        out.push_str(&format!(" <a href=\"https://doi.org/{}\">doi:{}</a>", doi, doi));
+    } else if let Some(url) = entry.field("url") {
+        out.push_str(&format!(" <a href=\"{}\">{}</a>", url, url));
+    }
+
+    out
+}
+
+/// Split a list of names at `and` outside of braces and parse each name
+///
+/// Names are either written as `Last, First` or as `First von Last`, braces keep several words
+/// together like in `{Barnes and Noble}`.
+fn parse_names(value: &str) -> Vec<Name> {
+    let mut names = vec![Vec::new()];
+    for word in split_words(value) {
+        if word.eq_ignore_ascii_case("and") {
+            names.push(Vec::new());
+        } else {
+            names.last_mut().unwrap().push(word);
+        }
+    }
+
+    names.into_iter().filter(|x| !x.is_empty()).map(|words| {
+        let name = words.join(" ");
+
+        // a comma outside of braces separates the last name
+        let mut depth = 0;
+        let comma = name.char_indices().find(|(_, x)| {
+            match x {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => {}
+            }
+            *x == ',' && depth == 0
+        });
+
+        if let Some((pos, _)) = comma {
+            let first = name[pos + 1..].rsplit(',').next().unwrap();
+
+            return Name { first: latex_to_text(first.trim()), last: latex_to_text(name[..pos].trim()) };
+        }
+
+        // particles starting with a lowercase letter belong to the last name
+        let mut split = words.len() - 1;
+        while split > 0 && words[split - 1].chars().next().map(|x| x.is_lowercase()).unwrap_or(false) {
+            split -= 1;
+        }
+
+        Name { first: latex_to_text(&words[..split].join(" ")), last: latex_to_text(&words[split..].join(" ")) }
+    }).collect()
+}
+
+/// Split at whitespace outside of braces
+fn split_words(value: &str) -> Vec<String> {
+    let mut words = vec![String::new()];
+    let mut depth = 0;
+
+    for x in value.chars() {
+        match x {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            _ => {}
+        }
+
+        if x.is_whitespace() && depth == 0 {
+            if !words.last().unwrap().is_empty() {
+                words.push(String::new());
+            }
+        } else {
+            words.last_mut().unwrap().push(x);
+        }
+    }
+
+    words.into_iter().filter(|x| !x.is_empty()).collect()
+}
+
+/// Replace LaTeX markup of a field by text and escape it for HTML
+///
+/// Accents become combining characters, braces are removed and math stays intact, so that it
+/// is rendered like inline math in the text.
+fn latex_to_text(value: &str) -> String {
+    const ACCENTS: [(char, char); 12] = [
+        ('"', '\u{308}'), ('\'', '\u{301}'), ('`', '\u{300}'), ('^', '\u{302}'), ('~', '\u{303}'), ('=', '\u{304}'),
+        ('.', '\u{307}'), ('c', '\u{327}'), ('u', '\u{306}'), ('v', '\u{30c}'), ('H', '\u{30b}'), ('k', '\u{328}'),
+    ];
+    const SYMBOLS: [(&str, &str); 13] = [
+        ("ss", "ß"), ("o", "ø"), ("O", "Ø"), ("aa", "å"), ("AA", "Å"), ("ae", "æ"), ("AE", "Æ"),
+        ("oe", "œ"), ("OE", "Œ"), ("l", "ł"), ("L", "Ł"), ("TeX", "TeX"), ("LaTeX", "LaTeX"),
+    ];
+
+    let mut out = String::new();
+    let mut chars = value.chars().peekable();
+    let mut in_math = false;
+
+    while let Some(x) = chars.next() {
+        match x {
+            '$' => {
+                in_math = !in_math;
+                out.push('$');
+            },
+            _ if in_math => out.push(x),
+            '{' | '}' => {},
+            '~' => out.push('\u{a0}'),
+            '-' if chars.peek() == Some(&'-') => {
+                chars.next();
+                if chars.peek() == Some(&'-') {
+                    chars.next();
+                    out.push('—');
+                } else {
+                    out.push('–');
+                }
+            },
+            '\\' => {
+                let next = match chars.next() {
+                    Some(next) => next,
+                    None => break,
+                };
+
+                // accents named by a letter, like `\c`, are not the start of a longer command
+                let accent = ACCENTS.iter().find(|(x, _)| *x == next)
+                    .filter(|_| !next.is_alphabetic() || !chars.peek().map(|x| x.is_alphabetic()).unwrap_or(false));
+
+                if let Some((_, accent)) = accent {
+                    // the accented letter may be in braces, and `\c` needs a space like in `\c c`
+                    while chars.peek() == Some(&'{') || (next.is_alphabetic() && chars.peek() == Some(&' ')) {
+                        chars.next();
+                    }
+                    if let Some(letter) = chars.next() {
+                        out.push(if letter == '\\' { chars.next().unwrap_or('i') } else { letter });
+                        out.push(*accent);
+                    }
+                } else if next.is_alphabetic() {
+                    let mut command = next.to_string();
+                    while let Some(x) = chars.peek().filter(|x| x.is_alphabetic()) {
+                        command.push(*x);
+                        chars.next();
+                    }
+                    if chars.peek() == Some(&' ') {
+                        chars.next();
+                    }
+
+                    // other commands like `\emph` are dropped and keep their argument
+                    match SYMBOLS.iter().find(|(x, _)| *x == command) {
+                        Some((_, symbol)) => out.push_str(symbol),
+                        None if command == "i" => out.push('ı'),
+                        None => {}
+                    }
+                } else if next == '$' {
+                    out.push_str("&#36;");
+                } else {
+                    out.push(next);
+                }
+            },
+            _ => out.push(x),
+        }
+    }
+
+    // dotless i with an accent is displayed as a normal i
+    let out = out.replace("ı\u{301}", "í").replace("ı\u{308}", "ï").replace("ı\u{300}", "ì").replace("ı\u{302}", "î");
+
+    escape_html(&out)
+}
+
+/// Escape text for HTML, leaving entities like `&#36;` intact
+fn escape_html(value: &str) -> String {
+    let mut out = String::new();
+    for (i, x) in value.char_indices() {
+        match x {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' if !value[i..].starts_with("&#36;") => out.push_str("&amp;"),
+            x => out.push(x),
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const BIBTEX: &str = r#"
+@book{knuth84, author = {Donald E. Knuth}, title = {The TeXbook}, year = {1984}, edition = {2nd}}
+@article{knuth84b, author = {Knuth, Donald E.}, title = {Literate Programming}, journal = {The Computer Journal}, year = {1984}, pages = {97--111}}
+@techreport{kl94, author = {Knuth, Donald E. and Lamport, Leslie}, title = {Typesetting}, year = {1994}, number = {42}}
+@phdthesis{many, author = {Ada A and Ben B and Cay C and Dan D and Eve E}, title = {Many}, school = {MIT}, year = {1999}}
+@misc{ramanujan, author = {Ramanujan, Srinivasa}, title = {Notes}, year = {२०२१}}
+"#;
+
+    fn bibliography(name: &str, style: Style, locale: &Locale) -> Bibliography {
+        let path = env::temp_dir().join(format!("mdbook-scientific-{}-{}.bib", name, std::process::id()));
+        fs::write(&path, BIBTEX).unwrap();
+        let bibliography = Bibliography::from_file(&path, style, locale).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        bibliography
+    }
+
+    fn labels(bibliography: &Bibliography) -> Vec<(&str, &str)> {
+        bibliography.entries().iter().map(|x| (x.key.as_str(), x.label.as_str())).collect()
+    }
+
+    #[test]
+    fn alpha_labels() {
+        let locale = Locale::from_config(None, None, &[]).unwrap();
+        let bibliography = bibliography("alpha", Style::Alpha, &locale);
+
+        let stems = bibliography.entries().iter().map(|x| (x.key.as_str(), alpha_label(x))).collect::<Vec<_>>();
+        assert!(stems.contains(&("kl94", "KL94".into())));
+        assert!(stems.contains(&("many", "ABC+99".into())));
+        assert!(stems.contains(&("ramanujan", "Ram२१".into())));
+
+        assert_eq!(labels(&bibliography), [
+            ("many", "ABC+99"), ("kl94", "KL94"), ("knuth84b", "Knu84a"), ("knuth84", "Knu84b"), ("ramanujan", "Ram२१"),
+        ]);
+    }
+
+    #[test]
+    fn author_year_labels() {
+        let locale = Locale::from_config(None, None, &[]).unwrap();
+        let bibliography = bibliography("author-year", Style::AuthorYear, &locale);
+
+        assert_eq!(labels(&bibliography), [
+            ("many", "A et al., 1999"), ("knuth84b", "Knuth, 1984a"), ("knuth84", "Knuth, 1984b"),
+            ("kl94", "Knuth and Lamport, 1994"), ("ramanujan", "Ramanujan, २०२१"),
+        ]);
+    }
+
+    #[test]
+    fn localized_entries() {
+        let cfg = "language = 'de'".parse::<toml::Value>().unwrap();
+        let locale = Locale::from_config(None, Some(&cfg), &[]).unwrap();
+        let html = bibliography("plain", Style::Plain, &locale).to_html(&locale);
+
+        assert!(html.contains("2nd Auflage"), "{}", html);
+        assert!(html.contains("S. 97–111"), "{}", html);
+        assert!(html.contains("Technischer Bericht 42"), "{}", html);
+        assert!(html.contains("Dissertation"), "{}", html);
+        assert!(!html.contains("thesis"), "{}", html);
+    }
+}
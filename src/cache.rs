@@ -0,0 +1,389 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::fs::TryLockError;
+use std::time::{Duration, SystemTime};
+
+use log::{info, warn};
+
+use crate::error::{Error, Result};
+
+/// Name of the manifest file listing all completely rendered fragments
+const MANIFEST: &str = "manifest";
+/// Name of the lock file, which is locked while a build is running
+const LOCK: &str = ".lock";
+/// Directory containing the private working directories of each fragment
+const TMP_DIR: &str = ".tmp";
+/// Name of the shared store inside the user's cache directory
+const SHARED_DIR: &str = "mdbook-scientific";
+/// Age after which a temporary file in the shared store is left over from a killed build
+const STALE_TMP: Duration = Duration::from_secs(60 * 60);
+
+/// Lock file guarding the fragment directory against concurrent builds
+///
+/// The file itself stays in place, it is locked exclusively with an OS file lock while a build
+/// runs. The operating system releases the lock when the file is closed, also when a build is
+/// killed, so a lock can never become stale.
+struct LockFile {
+    _file: File,
+}
+
+impl LockFile {
+    fn acquire(path: PathBuf) -> Result<LockFile> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path).map_err(Error::Io)?;
+
+        match file.try_lock() {
+            Ok(()) => {},
+            Err(TryLockError::WouldBlock) => {
+                info!("Waiting for another build to release `{}`", path.display());
+                file.lock().map_err(Error::Io)?;
+            },
+            Err(TryLockError::Error(err)) => return Err(Error::Io(err)),
+        }
+
+        // the process holding the lock, for debugging only
+        file.set_len(0).and_then(|_| write!(file, "{}", std::process::id())).map_err(Error::Io)?;
+
+        Ok(LockFile { _file: file })
+    }
+}
+
+/// User-level store of rendered fragments shared between books
+///
+/// The store is content-addressed by the fragment name, which is a hash of everything influencing
+/// the rendering. Entries are written atomically, so every file in the store is complete. The
+/// modification time of an entry is bumped on every use and the least recently used entries are
+/// evicted once the store grows beyond its size limit.
+pub struct SharedStore {
+    path: PathBuf,
+    limit: u64,
+}
+
+impl SharedStore {
+    /// Open the store in `$XDG_CACHE_HOME` (or `~/.cache`) with a size limit in bytes
+    pub fn open(limit: u64) -> Result<SharedStore> {
+        let base = env::var_os("XDG_CACHE_HOME")
+            .filter(|x| !x.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|x| Path::new(&x).join(".cache")))
+            .ok_or_else(|| Error::Io(io::Error::new(io::ErrorKind::NotFound, "neither XDG_CACHE_HOME nor HOME is set")))?;
+
+        let path = base.join(SHARED_DIR);
+        fs::create_dir_all(&path).map_err(Error::Io)?;
+
+        Ok(SharedStore { path, limit })
+    }
+
+    /// Place a copy of `file` at `dest`, returns false if the store does not contain it
+    fn get(&self, file: &str, dest: &Path) -> bool {
+        let source = self.path.join(file);
+        if !source.is_file() {
+            return false;
+        }
+
+        self.touch(file);
+
+        // hard links are cheap, but fail across file systems
+        fs::hard_link(&source, dest).or_else(|_| fs::copy(&source, dest).map(|_| ())).is_ok()
+    }
+
+    /// Add a file to the store
+    fn insert(&self, file: &str, source: &Path) -> Result<()> {
+        if self.path.join(file).is_file() {
+            return Ok(());
+        }
+
+        // another build may insert the same fragment, write to a private name first
+        let tmp = self.path.join(format!(".{}.{}", std::process::id(), file));
+        fs::copy(source, &tmp)
+            .and_then(|_| fs::rename(&tmp, self.path.join(file)))
+            .map_err(|err| {
+                let _ = fs::remove_file(&tmp);
+                Error::Io(err)
+            })
+    }
+
+    /// Mark an entry as recently used
+    fn touch(&self, file: &str) {
+        if let Ok(file) = OpenOptions::new().write(true).open(self.path.join(file)) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+    }
+
+    /// Remove the least recently used entries until the store fits into its size limit
+    ///
+    /// Temporary files of killed builds are removed once they are older than `STALE_TMP`, younger
+    /// ones may still be written by a running build.
+    fn evict(&self) -> io::Result<()> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.path)?.filter_map(|entry| entry.ok()) {
+            let metadata = match entry.metadata() {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            let modified = match metadata.modified() {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+
+            if !entry.file_name().to_string_lossy().starts_with('.') {
+                entries.push((modified, metadata.len(), entry.path()));
+            } else if modified.elapsed().map(|x| x > STALE_TMP).unwrap_or(false) {
+                remove_entry(&entry.path())?;
+            }
+        }
+
+        let mut size: u64 = entries.iter().map(|x| x.1).sum();
+        if size <= self.limit {
+            return Ok(());
+        }
+
+        entries.sort();
+        for (_, len, path) in entries {
+            if size <= self.limit {
+                break;
+            }
+
+            remove_entry(&path)?;
+            size -= len;
+        }
+
+        Ok(())
+    }
+}
+
+/// Remove a file of the shared store, another build may have removed it already
+fn remove_entry(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+impl Drop for SharedStore {
+    fn drop(&mut self) {
+        if let Err(err) = self.evict() {
+            warn!("Could not evict fragments from `{}`: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// Cache of rendered fragments
+///
+/// Every fragment is rendered in a private temporary directory and the finished SVG is then
+/// atomically renamed into the cache. Only fragments listed in the manifest with a matching file
+/// size are considered valid, so outputs of interrupted runs are never reused.
+pub struct Cache {
+    path: PathBuf,
+    manifest: RefCell<HashMap<String, u64>>,
+    shared: Option<SharedStore>,
+    _lock: LockFile,
+}
+
+impl Cache {
+    /// Open the cache at the given path and lock it for the duration of the build
+    ///
+    /// If a shared store is given, fragments missing in the cache are looked up there first and
+    /// newly rendered fragments are added to it.
+    pub fn open(path: &Path, shared: Option<SharedStore>) -> Result<Cache> {
+        fs::create_dir_all(path).map_err(Error::Io)?;
+        let path = path.canonicalize().map_err(Error::Io)?;
+
+        let lock = LockFile::acquire(path.join(LOCK))?;
+
+        // the manifest is append-only, a truncated last line is simply ignored
+        let content = fs::read_to_string(path.join(MANIFEST)).unwrap_or_default();
+        let manifest = content
+            .lines()
+            .filter_map(|line| {
+                let mut elms = line.splitn(2, ' ');
+                match (elms.next(), elms.next().map(|x| x.parse::<u64>())) {
+                    (Some(file), Some(Ok(len))) => Some((file.to_string(), len)),
+                    _ => None
+                }
+            })
+            .collect::<HashMap<_, _>>();
+
+        // remove working directories of previously interrupted builds
+        let _ = fs::remove_dir_all(path.join(TMP_DIR));
+
+        let cache = Cache {
+            path,
+            manifest: RefCell::new(manifest),
+            shared,
+            _lock: lock,
+        };
+
+        // rewritten lines and fragments removed by hand are dropped from the manifest
+        if content.lines().count() > cache.manifest.borrow().len() {
+            cache.compact()?;
+        }
+
+        Ok(cache)
+    }
+
+    /// Rewrite the manifest with one line for each valid fragment
+    fn compact(&self) -> Result<()> {
+        self.manifest.borrow_mut().retain(|file, len| {
+            fs::metadata(self.path.join(file)).map(|x| x.is_file() && x.len() == *len).unwrap_or(false)
+        });
+
+        let mut content = self.manifest.borrow().iter().map(|(file, len)| format!("{} {}\n", file, len)).collect::<Vec<_>>();
+        content.sort();
+
+        // the manifest is replaced atomically, a partial rewrite would lose valid fragments
+        let tmp = self.path.join(format!("{}.tmp", MANIFEST));
+        File::create(&tmp)
+            .and_then(|mut file| file.write_all(content.concat().as_bytes()).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&tmp, self.path.join(MANIFEST)))
+            .map_err(Error::Io)
+    }
+
+    /// Path of the cache directory
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Check whether a file is completely rendered and unchanged since
+    fn is_valid(&self, file: &str) -> bool {
+        match (self.manifest.borrow().get(file), fs::metadata(self.path.join(file))) {
+            (Some(len), Ok(metadata)) => metadata.is_file() && metadata.len() == *len,
+            _ => false
+        }
+    }
+
+    /// Return the SVG file of fragment `name`, rendering it first if necessary
+    ///
+    /// The render function gets a path without extension inside a private working directory and
+    /// has to produce the file `<path>.svg` there.
+    pub fn fetch<F>(&self, name: &str, render: F) -> Result<String>
+    where
+        F: FnOnce(&Path) -> Result<()>,
+    {
+        let file = format!("{}.svg", name);
+        if self.is_valid(&file) {
+            // also publish fragments rendered before the store was enabled
+            if let Some(shared) = &self.shared {
+                if shared.insert(&file, &self.path.join(&file)).is_ok() {
+                    shared.touch(&file);
+                }
+            }
+
+            return Ok(file);
+        }
+
+        let work_dir = self.path.join(TMP_DIR).join(name);
+        if work_dir.exists() {
+            fs::remove_dir_all(&work_dir).map_err(Error::Io)?;
+        }
+        fs::create_dir_all(&work_dir).map_err(Error::Io)?;
+
+        let output = work_dir.join(&file);
+        let res = match &self.shared {
+            Some(shared) if shared.get(&file, &output) => self.commit(&output, &file),
+            Some(shared) => render(&work_dir.join(name))
+                .and_then(|_| shared.insert(&file, &output))
+                .and_then(|_| self.commit(&output, &file)),
+            None => render(&work_dir.join(name))
+                .and_then(|_| self.commit(&output, &file)),
+        };
+
+        let _ = fs::remove_dir_all(&work_dir);
+
+        res.map(|_| file)
+    }
+
+    /// Move a finished file into the cache and record it in the manifest
+    fn commit(&self, source: &Path, file: &str) -> Result<()> {
+        let len = fs::metadata(source).map_err(Error::Io)?.len();
+
+        // flush the content before making it visible in the cache
+        File::open(source).and_then(|x| x.sync_all()).map_err(Error::Io)?;
+        fs::rename(source, self.path.join(file)).map_err(Error::Io)?;
+
+        let mut manifest = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.path.join(MANIFEST))
+            .map_err(Error::Io)?;
+
+        writeln!(manifest, "{} {}", file, len).map_err(Error::Io)?;
+        manifest.sync_all().map_err(Error::Io)?;
+
+        self.manifest.borrow_mut().insert(file.to_string(), len);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("mdbook-scientific-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+
+        path
+    }
+
+    fn render(path: &Path) -> Result<()> {
+        fs::write(path.with_extension("svg"), "<svg/>").map_err(Error::Io)
+    }
+
+    #[test]
+    fn fragments_are_reused() {
+        let path = temp_dir("reuse");
+        assert_eq!(Cache::open(&path, None).unwrap().fetch("a", render).unwrap(), "a.svg");
+
+        let cache = Cache::open(&path, None).unwrap();
+        assert_eq!(cache.fetch("a", |_| panic!("rendered twice")).unwrap(), "a.svg");
+        drop(cache);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn manifest_is_compacted() {
+        let path = temp_dir("compact");
+        let cache = Cache::open(&path, None).unwrap();
+        cache.fetch("a", render).unwrap();
+        cache.fetch("b", render).unwrap();
+        drop(cache);
+
+        // a duplicate line, a fragment removed by hand and a truncated line
+        fs::remove_file(path.join("b.svg")).unwrap();
+        let mut manifest = OpenOptions::new().append(true).open(path.join(MANIFEST)).unwrap();
+        write!(manifest, "a.svg 6\nc.svg").unwrap();
+        drop(manifest);
+
+        drop(Cache::open(&path, None).unwrap());
+        assert_eq!(fs::read_to_string(path.join(MANIFEST)).unwrap(), "a.svg 6\n");
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn stale_files_are_evicted() {
+        let path = temp_dir("stale");
+        fs::create_dir_all(&path).unwrap();
+        for file in [".1.a.svg", ".2.b.svg", "c.svg"] {
+            fs::write(path.join(file), "<svg/>").unwrap();
+        }
+        let file = OpenOptions::new().write(true).open(path.join(".1.a.svg")).unwrap();
+        file.set_modified(SystemTime::now() - 2 * STALE_TMP).unwrap();
+        drop(file);
+
+        let store = SharedStore { path: path.clone(), limit: 1024 };
+        store.evict().unwrap();
+        assert!(!path.join(".1.a.svg").exists());
+        assert!(path.join(".2.b.svg").exists());
+        assert!(path.join("c.svg").exists());
+        drop(store);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,680 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::bibliography::{alpha_label, Entry, Names};
+use crate::error::{Error, Result};
+use crate::locale::Locale;
+
+/// Terms used if the style does not define them, from the English locale of CSL
+const TERMS: [(&str, &str, &str); 14] = [
+    ("and", "and", "and"),
+    ("et-al", "et al.", "et al."),
+    ("in", "in", "in"),
+    ("no date", "n.d.", "n.d."),
+    ("page", "p.", "pp."),
+    ("editor", "ed.", "eds."),
+    ("volume", "vol.", "vols."),
+    ("issue", "no.", "nos."),
+    ("edition", "ed.", "eds."),
+    ("chapter", "chap.", "chaps."),
+    ("accessed", "accessed", "accessed"),
+    ("retrieved", "retrieved", "retrieved"),
+    ("from", "from", "from"),
+    ("open-quote", "“", "“"),
+];
+
+/// Name options which are inherited from the style, citation and bibliography elements
+const INHERITED: [&str; 9] = [
+    "and", "delimiter-precedes-last", "et-al-min", "et-al-use-first", "initialize-with",
+    "name-as-sort-order", "sort-separator", "name-form", "names-delimiter",
+];
+
+/// Element of a style with its attributes and children
+#[derive(Debug, Default)]
+struct Node {
+    name: String,
+    attrs: HashMap<String, String>,
+    children: Vec<Node>,
+    /// Text content of terms
+    text: String,
+}
+
+impl Node {
+    fn from_xml(node: roxmltree::Node) -> Node {
+        Node {
+            name: node.tag_name().name().to_string(),
+            attrs: node.attributes().map(|x| (x.name().to_string(), x.value().to_string())).collect(),
+            children: node.children().filter(|x| x.is_element()).map(Node::from_xml).collect(),
+            text: node.text().unwrap_or("").trim().to_string(),
+        }
+    }
+
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.get(name).map(|x| x.as_str())
+    }
+
+    fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|x| x.name == name)
+    }
+}
+
+/// Rendered text and whether it contains variables, to suppress groups with only empty variables
+#[derive(Default)]
+struct Output {
+    text: String,
+    /// Number of variables which were looked up
+    called: usize,
+    /// Number of variables which were not empty
+    found: usize,
+}
+
+impl Output {
+    fn text(text: String) -> Output {
+        Output { text, called: 0, found: 0 }
+    }
+
+    fn variable(text: Option<String>) -> Output {
+        let found = text.is_some() as usize;
+        Output { text: text.unwrap_or_default(), called: 1, found }
+    }
+
+    fn extend(&mut self, other: Output, delimiter: &str) {
+        if !other.text.is_empty() {
+            if !self.text.is_empty() {
+                self.text.push_str(delimiter);
+            }
+            self.text.push_str(&other.text);
+        }
+        self.called += other.called;
+        self.found += other.found;
+    }
+}
+
+/// Citation style parsed from a CSL file
+///
+/// Only the subset of CSL which is needed by common styles is supported: text, names, dates with
+/// years, numbers, labels, groups and conditions, as well as macros, sorting and terms.
+#[derive(Debug)]
+pub struct Csl {
+    /// Inherited name options of the style element
+    options: HashMap<String, String>,
+    macros: HashMap<String, Node>,
+    citation: Node,
+    bibliography: Option<Node>,
+    /// Terms defined by the style, with their single and multiple form keyed by name and form
+    terms: HashMap<(String, String), (String, String)>,
+    /// Genres of theses and reports in the language of the book, keyed by entry type
+    genres: HashMap<String, String>,
+}
+
+/// Entry with its label, which is rendered by a style
+struct Context<'a> {
+    entry: &'a Entry,
+    /// Name options inherited from the enclosing elements
+    options: HashMap<String, String>,
+}
+
+impl Csl {
+    /// Read and parse a CSL file
+    pub fn from_file(path: &Path, locale: &Locale) -> Result<Csl> {
+        let source = fs::read_to_string(path).map_err(Error::Io)?;
+        let doc = roxmltree::Document::parse(&source)
+            .map_err(|err| Error::InvalidBibliography(format!("could not parse style {}: {}", path.display(), err)))?;
+        let style = Node::from_xml(doc.root_element());
+
+        if style.name != "style" {
+            return Err(Error::InvalidBibliography(format!("{} is not a CSL style", path.display())));
+        }
+
+        let options = INHERITED.iter()
+            .filter_map(|key| style.attr(key).map(|x| (key.to_string(), x.to_string())))
+            .collect();
+
+        let mut macros = HashMap::new();
+        let mut citation = None;
+        let mut bibliography = None;
+        let mut terms = HashMap::new();
+
+        for node in style.children {
+            match node.name.as_str() {
+                "macro" => { macros.insert(node.attr("name").unwrap_or("").to_string(), node); },
+                "citation" => citation = Some(node),
+                "bibliography" => bibliography = Some(node),
+                "locale" => {
+                    let all = node.child("terms").map(|x| x.children.iter()).into_iter().flatten();
+                    for term in all {
+                        let (single, multiple) = match (term.child("single"), term.child("multiple")) {
+                            (Some(single), Some(multiple)) => (single.text.clone(), multiple.text.clone()),
+                            _ => (term.text.clone(), term.text.clone()),
+                        };
+                        let form = term.attr("form").unwrap_or("long").to_string();
+                        terms.entry((term.attr("name").unwrap_or("").to_string(), form)).or_insert((single, multiple));
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        let citation = citation
+            .ok_or_else(|| Error::InvalidBibliography(format!("style {} has no citation element", path.display())))?;
+
+        let genres = [("phdthesis", "phd_thesis"), ("mastersthesis", "masters_thesis"), ("techreport", "report")].iter()
+            .filter_map(|(kind, key)| locale.get(key).map(|x| (kind.to_string(), x.to_string())))
+            .collect();
+
+        Ok(Csl { options, macros, citation, bibliography, terms, genres })
+    }
+
+    /// Affixes around all citations, like `[` and `]`, from the citation layout
+    pub fn citation_affixes(&self) -> (String, String) {
+        let layout = self.citation.child("layout");
+        let affix = |name| layout.and_then(|x| x.attr(name)).unwrap_or("").to_string();
+
+        (affix("prefix"), affix("suffix"))
+    }
+
+    /// Delimiter between the entries of a citation, like `; `
+    pub fn citation_delimiter(&self) -> Option<String> {
+        self.citation.child("layout").and_then(|x| x.attr("delimiter")).map(|x| x.to_string())
+    }
+
+    /// Whether the bibliography of the style is sorted by other keys than the citation number
+    pub fn sorted(&self) -> bool {
+        let keys = self.bibliography.as_ref().and_then(|x| x.child("sort")).map(|x| x.children.as_slice()).unwrap_or(&[]);
+
+        keys.iter().any(|key| key.attr("variable") != Some("citation-number") || key.attr("sort") == Some("descending"))
+    }
+
+    /// Sort entries by the keys of the bibliography, the order is kept without keys
+    pub fn sort(&self, entries: &mut Vec<Entry>) {
+        let keys = match self.bibliography.as_ref().and_then(|x| x.child("sort")) {
+            Some(sort) => &sort.children,
+            None => return,
+        };
+
+        let mut sorted = entries.iter().enumerate().map(|(i, entry)| {
+            let context = self.context(entry, self.bibliography.as_ref());
+            let values = keys.iter().map(|key| match (key.attr("variable"), key.attr("macro")) {
+                (Some("citation-number"), _) => format!("{:08}", i),
+                (Some(variable), _) if ["author", "editor"].contains(&variable) => {
+                    entry.names(variable).list.iter().map(|x| format!("{} {}", x.last, x.first)).collect::<Vec<_>>().join(" ")
+                },
+                (Some(variable), _) => self.variable(&context, variable).unwrap_or_default(),
+                (None, Some(name)) => self.macros.get(name)
+                    .map(|node| strip_tags(&self.render_children(node, &context, "").text))
+                    .unwrap_or_default(),
+                _ => String::new(),
+            }.to_lowercase()).collect::<Vec<_>>();
+
+            (values, i)
+        }).collect::<Vec<_>>();
+
+        sorted.sort_by(|(a, i), (b, j)| {
+            keys.iter().zip(a.iter().zip(b.iter()))
+                .map(|(key, (a, b))| match key.attr("sort") {
+                    Some("descending") => b.cmp(a),
+                    _ => a.cmp(b),
+                })
+                .find(|x| x.is_ne())
+                .unwrap_or(i.cmp(j))
+        });
+
+        let mut taken = entries.drain(..).map(Some).collect::<Vec<_>>();
+        *entries = sorted.into_iter().map(|(_, i)| taken[i].take().unwrap()).collect();
+    }
+
+    /// Citation of a single entry without the affixes of the layout
+    ///
+    /// The delimiter of the layout separates the entries of a citation, see `citation_delimiter`.
+    pub fn citation(&self, entry: &Entry) -> String {
+        let context = self.context(entry, Some(&self.citation));
+
+        match self.citation.child("layout") {
+            Some(layout) => format_text(layout, self.render_children(layout, &context, "").text),
+            None => entry.number.to_string(),
+        }
+    }
+
+    /// Bibliography entry, or the citation if the style has no bibliography
+    pub fn bibliography(&self, entry: &Entry) -> String {
+        let bibliography = match self.bibliography.as_ref() {
+            Some(x) => x,
+            None => return self.citation(entry),
+        };
+
+        let context = self.context(entry, Some(bibliography));
+        match bibliography.child("layout") {
+            Some(layout) => self.affixes(layout, self.render_children(layout, &context, "").text),
+            None => self.citation(entry),
+        }
+    }
+
+    fn context<'a>(&self, entry: &'a Entry, element: Option<&Node>) -> Context<'a> {
+        let mut options = self.options.clone();
+        if let Some(element) = element {
+            options.extend(INHERITED.iter().filter_map(|key| element.attr(key).map(|x| (key.to_string(), x.to_string()))));
+        }
+
+        Context { entry, options }
+    }
+
+    /// Look up a term in the style and then in the defaults
+    fn term(&self, name: &str, form: &str, plural: bool) -> String {
+        let term = self.terms.get(&(name.to_string(), form.to_string()))
+            .or_else(|| self.terms.get(&(name.to_string(), "long".to_string())))
+            .map(|(single, multiple)| (single.as_str(), multiple.as_str()));
+
+        let (single, multiple) = term.unwrap_or_else(|| TERMS.iter()
+            .find(|(x, _, _)| *x == name)
+            .map(|(_, single, multiple)| (*single, *multiple))
+            .unwrap_or(("", "")));
+
+        if plural { multiple.to_string() } else { single.to_string() }
+    }
+
+    /// Value of a variable of an entry, mapped from the BibTeX fields
+    fn variable(&self, context: &Context, name: &str) -> Option<String> {
+        let entry = context.entry;
+        let field = |name| entry.field(name).map(|x| x.to_string());
+
+        match name {
+            "citation-number" => Some(entry.number.to_string()),
+            "citation-label" => Some(alpha_label(entry)),
+            "title" => field("title"),
+            "container-title" => field("journal").or_else(|| field("booktitle")),
+            "collection-title" => field("series"),
+            "issue" if entry.kind() == "article" => field("number"),
+            "number" if entry.kind() != "article" => field("number"),
+            "page" => field("pages"),
+            "publisher" => field("publisher").or_else(|| field("school")).or_else(|| field("institution")),
+            "publisher-place" => field("address"),
+            "genre" => self.genres.get(entry.kind()).cloned().or_else(|| field("type")),
+            "issued" => Some(entry.year().to_string()).filter(|x| !x.is_empty()),
+            "DOI" | "URL" | "ISBN" | "ISSN" => field(&name.to_lowercase()),
+            "volume" | "edition" | "note" | "abstract" | "version" => field(name),
+            _ => None,
+        }
+    }
+
+    /// Type of an entry in CSL, mapped from the BibTeX entry type
+    fn entry_type(entry: &Entry) -> &'static str {
+        match entry.kind() {
+            "article" => "article-journal",
+            "book" | "proceedings" => "book",
+            "inproceedings" | "conference" => "paper-conference",
+            "incollection" | "inbook" => "chapter",
+            "phdthesis" | "mastersthesis" => "thesis",
+            "techreport" => "report",
+            "online" => "webpage",
+            _ => "article",
+        }
+    }
+
+    fn render_children(&self, node: &Node, context: &Context, delimiter: &str) -> Output {
+        let mut out = Output::default();
+        for child in node.children.iter() {
+            out.extend(self.render(child, context), delimiter);
+        }
+
+        out
+    }
+
+    /// Render an element with its formatting and affixes
+    fn render(&self, node: &Node, context: &Context) -> Output {
+        let mut out = match node.name.as_str() {
+            "text" => {
+                if let Some(variable) = node.attr("variable") {
+                    Output::variable(self.variable(context, variable))
+                } else if let Some(name) = node.attr("macro") {
+                    match self.macros.get(name) {
+                        Some(node) => self.render_children(node, context, ""),
+                        None => Output::default(),
+                    }
+                } else if let Some(term) = node.attr("term") {
+                    Output::text(self.term(term, node.attr("form").unwrap_or("long"), node.attr("plural") == Some("true")))
+                } else {
+                    Output::text(escape(node.attr("value").unwrap_or("")))
+                }
+            },
+            "number" => Output::variable(node.attr("variable").and_then(|x| self.variable(context, x))),
+            "date" => Output::variable(node.attr("variable").and_then(|x| self.variable(context, x))),
+            "label" => {
+                let variable = node.attr("variable").unwrap_or("");
+                match self.variable(context, variable) {
+                    Some(value) => {
+                        let plural = value.contains(['–', '-', ',', '&']);
+                        Output::text(self.term(variable, node.attr("form").unwrap_or("long"), plural))
+                    },
+                    None => Output::default(),
+                }
+            },
+            "names" => self.render_names(node, context),
+            "group" => {
+                let out = self.render_children(node, context, node.attr("delimiter").unwrap_or(""));
+
+                // groups with variables, which are all empty, are suppressed
+                if out.called > 0 && out.found == 0 {
+                    Output { text: String::new(), ..out }
+                } else {
+                    out
+                }
+            },
+            "choose" => {
+                let branch = node.children.iter().find(|x| x.name == "else" || self.condition(x, context));
+                match branch {
+                    Some(branch) => self.render_children(branch, context, ""),
+                    None => Output::default(),
+                }
+            },
+            _ => Output::default(),
+        };
+
+        if !out.text.is_empty() {
+            out.text = self.affixes(node, format_text(node, out.text));
+        }
+
+        out
+    }
+
+    fn affixes(&self, node: &Node, text: String) -> String {
+        if text.is_empty() {
+            return text;
+        }
+
+        format!("{}{}{}", escape(node.attr("prefix").unwrap_or("")), text, escape(node.attr("suffix").unwrap_or("")))
+    }
+
+    /// Evaluate the conditions of `if` and `else-if`
+    fn condition(&self, node: &Node, context: &Context) -> bool {
+        let mut tests = Vec::new();
+        for (attr, values) in node.attrs.iter() {
+            for value in values.split_whitespace() {
+                tests.push(match attr.as_str() {
+                    "type" => Csl::entry_type(context.entry) == value,
+                    "variable" => match value {
+                        "author" | "editor" => !context.entry.names(value).list.is_empty(),
+                        _ => self.variable(context, value).is_some(),
+                    },
+                    "is-numeric" => self.variable(context, value)
+                        .map(|x| x.chars().any(|x| x.is_ascii_digit()) && x.chars().all(|x| x.is_ascii_digit() || "-–, ".contains(x)))
+                        .unwrap_or(false),
+                    _ => continue,
+                });
+            }
+        }
+
+        match node.attr("match") {
+            Some("any") => tests.iter().any(|x| *x),
+            Some("none") => !tests.iter().any(|x| *x),
+            _ => tests.iter().all(|x| *x),
+        }
+    }
+
+    /// Render a list of names, or the first non-empty element of `substitute` if there are none
+    fn render_names(&self, node: &Node, context: &Context) -> Output {
+        let variables = node.attr("variable").unwrap_or("author");
+        let name = node.child("name");
+        let option = |key: &str| name.and_then(|x| x.attr(key))
+            .or_else(|| context.options.get(key).map(|x| x.as_str()));
+
+        let mut out = Output::default();
+        for variable in variables.split_whitespace() {
+            let names = context.entry.names(variable);
+            out.called += 1;
+            if names.list.is_empty() {
+                continue;
+            }
+            out.found += 1;
+
+            let mut text = self.format_names(names, name, &option);
+            if let Some(label) = node.child("label") {
+                let plural = names.list.len() > 1;
+                let term = self.term(variable, label.attr("form").unwrap_or("long"), plural);
+                text = format!("{}{}", text, self.affixes(label, format_text(label, term)));
+            }
+
+            out.extend(Output::text(text), option("names-delimiter").or_else(|| node.attr("delimiter")).unwrap_or(", "));
+        }
+
+        if out.found == 0 {
+            if let Some(substitute) = node.child("substitute") {
+                for child in substitute.children.iter() {
+                    let res = self.render(child, context);
+                    if !res.text.is_empty() {
+                        return res;
+                    }
+                }
+            }
+        }
+
+        out
+    }
+
+    fn format_names<'a>(&self, names: &Names, name: Option<&Node>, option: &dyn Fn(&str) -> Option<&'a str>) -> String {
+        let form = option("form").or_else(|| option("name-form")).unwrap_or("long");
+        if form == "count" {
+            return names.list.len().to_string();
+        }
+
+        let delimiter = option("delimiter").unwrap_or(", ");
+        let sort_order = option("name-as-sort-order");
+        let sort_separator = option("sort-separator").unwrap_or(", ");
+
+        // truncate long lists, `and others` in the BibTeX file is always shortened
+        let min = option("et-al-min").and_then(|x| x.parse().ok()).unwrap_or(usize::MAX);
+        let first = option("et-al-use-first").and_then(|x| x.parse().ok()).unwrap_or(1);
+        let truncated = names.list.len() >= min || names.others;
+        let count = if names.list.len() >= min { first.min(names.list.len()) } else { names.list.len() };
+
+        let mut list = names.list.iter().take(count).enumerate().map(|(i, x)| {
+            let first = match option("initialize-with") {
+                Some(initials) => x.initials(initials),
+                None => x.first.clone(),
+            };
+
+            let inverted = sort_order == Some("all") || (sort_order == Some("first") && i == 0);
+            match (form, first.is_empty()) {
+                ("short", _) | (_, true) => x.last.clone(),
+                _ if inverted => format!("{}{}{}", x.last, sort_separator, first),
+                _ => format!("{} {}", first, x.last),
+            }
+        }).collect::<Vec<_>>();
+
+        let formatted = list.iter()
+            .map(|x| name.map(|node| format_text(node, x.clone())).unwrap_or_else(|| x.clone()))
+            .collect::<Vec<_>>();
+        list = formatted;
+
+        if truncated {
+            let et_al = self.term("et-al", "long", false);
+            return format!("{}{}{}", list.join(delimiter), if list.len() > 1 { delimiter } else { " " }, et_al);
+        }
+
+        let and = match option("and") {
+            Some("symbol") => Some("&amp;".to_string()),
+            Some(_) => Some(self.term("and", "long", false)),
+            None => None,
+        };
+
+        match (list.len(), and) {
+            (0, _) => String::new(),
+            (1, _) => list.remove(0),
+            (_, None) => list.join(delimiter),
+            (len, Some(and)) => {
+                let last = list.pop().unwrap();
+                let precedes = match option("delimiter-precedes-last").unwrap_or("contextual") {
+                    "always" => true,
+                    "never" => false,
+                    _ => len > 2,
+                };
+
+                format!("{}{}{} {}", list.join(delimiter), if precedes { delimiter } else { " " }, and, last)
+            },
+        }
+    }
+}
+
+/// Apply the formatting attributes of an element
+fn format_text(node: &Node, mut text: String) -> String {
+    if node.attr("strip-periods") == Some("true") {
+        text = text.replace('.', "");
+    }
+
+    text = match node.attr("text-case") {
+        Some("lowercase") => text.to_lowercase(),
+        Some("uppercase") => text.to_uppercase(),
+        Some("capitalize-first") | Some("sentence") => {
+            let mut chars = text.chars();
+            chars.next().map(|x| x.to_uppercase().chain(chars).collect()).unwrap_or_default()
+        },
+        _ => text,
+    };
+
+    if node.attr("quotes") == Some("true") {
+        text = format!("“{}”", text);
+    }
+    if node.attr("font-style") == Some("italic") {
+        text = format!("<i>{}</i>", text);
+    }
+    if node.attr("font-weight") == Some("bold") {
+        text = format!("<b>{}</b>", text);
+    }
+    if node.attr("vertical-align") == Some("sup") {
+        text = format!("<sup>{}</sup>", text);
+    }
+
+    text
+}
+
+/// Escape literal text of the style for HTML
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Remove the markup of rendered text, for sorting
+fn strip_tags(text: &str) -> String {
+    let mut out = String::new();
+    let mut in_tag = false;
+    for x in text.chars() {
+        match x {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            x if !in_tag => out.push(x),
+            _ => {}
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bibliography::{Bibliography, Order, Style};
+    use std::env;
+
+    const STYLE: &str = r#"<style xmlns="http://purl.org/net/xbiblio/csl" class="in-text" version="1.0">
+  <citation>
+    <layout prefix="(" suffix=")" delimiter="; ">
+      <group delimiter=", ">
+        <names variable="author"><name form="short" and="symbol" et-al-min="3" et-al-use-first="1"/></names>
+        <date variable="issued"/>
+      </group>
+    </layout>
+  </citation>
+  <bibliography>
+    <sort><key variable="author"/><key variable="issued" sort="descending"/></sort>
+    <layout suffix=".">
+      <group delimiter=", ">
+        <names variable="author"><name name-as-sort-order="first" initialize-with=". " and="text"/></names>
+        <text variable="title"/>
+        <choose>
+          <if type="thesis"><text variable="genre"/></if>
+          <else-if variable="container-title"><text variable="container-title" font-style="italic"/></else-if>
+          <else><text value="Unpublished"/></else>
+        </choose>
+      </group>
+    </layout>
+  </bibliography>
+</style>"#;
+
+    const BIBTEX: &str = r#"
+@techreport{kl94, author = {Knuth, Donald E. and Lamport, Leslie}, title = {Typesetting}, year = {1994}}
+@article{knuth84, author = {Donald E. Knuth}, title = {Literate Programming}, journal = {The Computer Journal}, year = {1984}}
+@phdthesis{many, author = {Ada A and Ben B and Cay C}, title = {Many}, school = {MIT}, year = {1999}}
+@book{knuth86, author = {Donald E. Knuth}, title = {The METAFONTbook}, year = {1986}}
+"#;
+
+    const NUMERIC: &str = r#"<style xmlns="http://purl.org/net/xbiblio/csl" class="in-text" version="1.0">
+  <citation>
+    <layout prefix="[" suffix="]" delimiter=", "><text variable="citation-number"/></layout>
+  </citation>
+  <bibliography>
+    <layout><text variable="citation-number" prefix="[" suffix="] "/><text variable="title"/></layout>
+  </bibliography>
+</style>"#;
+
+    const NATURE: &str = r#"<style xmlns="http://purl.org/net/xbiblio/csl" class="in-text" version="1.0">
+  <citation>
+    <layout vertical-align="sup" delimiter=","><text variable="citation-number"/><text value="*"/></layout>
+  </citation>
+</style>"#;
+
+    fn bibliography(style: &str, locale: &Locale) -> Bibliography {
+        let dir = env::temp_dir().join(format!("mdbook-scientific-csl-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("style.csl"), style).unwrap();
+        fs::write(dir.join("refs.bib"), BIBTEX).unwrap();
+
+        let style = Style::Csl(Box::new(Csl::from_file(&dir.join("style.csl"), locale).unwrap()));
+        let bibliography = Bibliography::from_file(&dir.join("refs.bib"), style, locale).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        bibliography
+    }
+
+    #[test]
+    fn citations() {
+        let locale = Locale::from_config(None, None, &[]).unwrap();
+        let bibliography = bibliography(STYLE, &locale);
+
+        let labels = bibliography.entries().iter().map(|x| (x.key.as_str(), x.label.as_str())).collect::<Vec<_>>();
+        assert_eq!(labels, [
+            ("many", "A et al., 1999"), ("knuth86", "Knuth, 1986"), ("knuth84", "Knuth, 1984"), ("kl94", "Knuth &amp; Lamport, 1994"),
+        ]);
+    }
+
+    #[test]
+    fn entries() {
+        let cfg = "language = 'de'".parse::<toml::Value>().unwrap();
+        let locale = Locale::from_config(None, Some(&cfg), &[]).unwrap();
+        let html = bibliography(STYLE, &locale).to_html(&locale);
+
+        assert!(html.contains(">A, A., B. B, and C. C, Many, Dissertation.<"), "{}", html);
+        assert!(html.contains(">Knuth, D. E., Literate Programming, <i>The Computer Journal</i>.<"), "{}", html);
+        assert!(html.contains(">Knuth, D. E. and L. Lamport, Typesetting, Unpublished.<"), "{}", html);
+    }
+
+    #[test]
+    fn citation_order() {
+        let locale = Locale::from_config(None, None, &[]).unwrap();
+        let mut bibliography = bibliography(NUMERIC, &locale);
+
+        let cited = ["many".to_string(), "knuth86".to_string(), "kl94".to_string()];
+        bibliography.arrange(Order::Style, &cited, false, &[]).unwrap();
+
+        let labels = bibliography.entries().iter().map(|x| (x.key.as_str(), x.label.as_str())).collect::<Vec<_>>();
+        assert_eq!(labels, [("many", "1"), ("knuth86", "2"), ("kl94", "3"), ("knuth84", "4")]);
+    }
+
+    #[test]
+    fn citation_layout() {
+        let locale = Locale::from_config(None, None, &[]).unwrap();
+        let bibliography = bibliography(NATURE, &locale);
+
+        assert_eq!(bibliography.entries()[0].label, "<sup>1*</sup>");
+        assert_eq!(bibliography.citation_delimiter().as_deref(), Some(","));
+        assert_eq!(bibliography.citation_affixes(), Some((String::new(), String::new())));
+    }
+}
@@ -1,4 +1,4 @@
-use std::{io, result};
+use std::{fmt, io, result};
 
 pub type Result<T> = result::Result<T, Error>;
 
@@ -6,9 +6,31 @@ pub type Result<T> = result::Result<T, Error>;
 pub enum Error {
     InvalidMath(String, String, usize), // reason, element, line
     InvalidReference(String),
+    DuplicateLabel(String),
+    InvalidConfig(String),
+    InvalidBlock(String),
     InvalidBibliography(String),
     InvalidDvisvgm(String),
+    InvalidGnuplot(String),
     BinaryNotFound(which::Error),
     UnevenNumberDollar,
     Io(io::Error),
 }
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidMath(reason, element, line) => write!(f, "invalid math: {} in line {} of `{}`", reason, line, element),
+            Error::InvalidReference(err) => write!(f, "invalid reference: {}", err),
+            Error::DuplicateLabel(err) => write!(f, "duplicate label: {}", err),
+            Error::InvalidConfig(err) => write!(f, "invalid configuration: {}", err),
+            Error::InvalidBlock(err) => write!(f, "invalid block: {}", err),
+            Error::InvalidBibliography(err) => write!(f, "invalid bibliography: {}", err),
+            Error::InvalidDvisvgm(err) => write!(f, "dvisvgm failed: {}", err),
+            Error::InvalidGnuplot(err) => write!(f, "gnuplot failed: {}", err),
+            Error::BinaryNotFound(err) => write!(f, "binary not found: {}", err),
+            Error::UnevenNumberDollar => write!(f, "uneven number of `$` in a line"),
+            Error::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
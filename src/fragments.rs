@@ -1,12 +1,21 @@
-use std::{str, usize, io::Write};
+use std::{str, io::Write};
 use std::path::Path;
 use std::fs::{self, File};
 use std::process::{Command, Stdio};
 
 use sha2::{Digest, Sha256};
 
+use crate::cache::Cache;
 use crate::error::{Error, Result};
 
+/// Name of a fragment, derived from everything which influences its rendering
+///
+/// The name is used as key in the fragment cache and shared store, so it has to differ whenever
+/// the rendered output differs.
+fn fragment_name(kind: &str, zoom: f32, content: &str) -> String {
+    hash(&format!("{}\n{}\n{}\n{}", env!("CARGO_PKG_VERSION"), kind, zoom, content))
+}
+
 /// Convert input string to 24 character hash
 pub fn hash(input: &str) -> String {
     let mut sh = Sha256::new();
@@ -17,252 +26,223 @@ pub fn hash(input: &str) -> String {
 }
 
 /// Generate SVG file from latex file with given zoom
+///
+/// Expects the file `<path>.tex` and produces `<path>.dvi` and `<path>.svg` next to it.
 pub fn generate_svg_from_latex(path: &Path, zoom: f32) -> Result<()> {
     let dest_path = path.parent().unwrap();
     let file: &Path = path.file_name().unwrap().as_ref();
 
     // use latex to generate a dvi
     let dvi_path = path.with_extension("dvi");
-    if !dvi_path.exists() {
-        let latex_path = which::which("latex")
-            .map_err(|err| Error::BinaryNotFound(err))?;
-
-        let cmd = Command::new(latex_path)
-            .current_dir(&dest_path)
-            //.arg("--jobname").arg(&dvi_path)
-            .arg(&file.with_extension("tex"))
-            .output()
-            .expect("Could not spawn latex");
-
-        if !cmd.status.success() {
-            let buf = String::from_utf8_lossy(&cmd.stdout);
-
-            // latex prints error to the stdout, if this is empty, then something is fundamentally
-            // wrong with the latex binary (for example shared library error). In this case just
-            // exit the program
-            if buf.is_empty() {
-                let buf = String::from_utf8_lossy(&cmd.stderr);
-                panic!("Latex exited with `{}`", buf);
-            }
+    let latex_path = which::which("latex")
+        .map_err(Error::BinaryNotFound)?;
 
-            let err = buf
-                .split("\n")
-                .filter(|x| {
-                    (x.starts_with("! ") || x.starts_with("l.")) && !x.contains("Emergency stop")
-                })
-                .fold(("", "", usize::MAX), |mut err, elm| {
-                    if elm.starts_with("! ") {
-                        err.0 = elm;
-                    } else if elm.starts_with("l.") {
-                        let mut elms = elm[2..].splitn(2, " ").map(|x| x.trim());
-                        if let Some(Ok(val)) = elms.next().map(|x| x.parse::<usize>()) {
-                            err.2 = val;
-                        }
-                        if let Some(val) = elms.next() {
-                            err.1 = val;
-                        }
-                    }
+    let cmd = Command::new(latex_path)
+        .current_dir(dest_path)
+        .arg(file.with_extension("tex"))
+        .output()
+        .expect("Could not spawn latex");
 
-                    err
-                });
+    if !cmd.status.success() {
+        let buf = String::from_utf8_lossy(&cmd.stdout);
 
-            return Err(Error::InvalidMath(
-                err.0.to_string(),
-                err.1.to_string(),
-                err.2,
-            ));
+        // latex prints error to the stdout, if this is empty, then something is fundamentally
+        // wrong with the latex binary (for example shared library error). In this case just
+        // exit the program
+        if buf.is_empty() {
+            let buf = String::from_utf8_lossy(&cmd.stderr);
+            panic!("Latex exited with `{}`", buf);
         }
+
+        let err = buf
+            .split("\n")
+            .filter(|x| {
+                (x.starts_with("! ") || x.starts_with("l.")) && !x.contains("Emergency stop")
+            })
+            .fold(("", "", usize::MAX), |mut err, elm| {
+                if elm.starts_with("! ") {
+                    err.0 = elm;
+                } else if let Some(elm) = elm.strip_prefix("l.") {
+                    let mut elms = elm.splitn(2, " ").map(|x| x.trim());
+                    if let Some(Ok(val)) = elms.next().map(|x| x.parse::<usize>()) {
+                        err.2 = val;
+                    }
+                    if let Some(val) = elms.next() {
+                        err.1 = val;
+                    }
+                }
+
+                err
+            });
+
+        return Err(Error::InvalidMath(
+            err.0.to_string(),
+            err.1.to_string(),
+            err.2,
+        ));
     }
 
     // convert the dvi to a svg file with the woff font format
-    let svg_path = path.with_extension("svg");
-    if !svg_path.exists() && dvi_path.exists() {
-        let dvisvgm_path = which::which("dvisvgm")
-            .map_err(|err| Error::BinaryNotFound(err))?;
-
-        let cmd = Command::new(dvisvgm_path)
-            .current_dir(&dest_path)
-            .arg("-b")
-            .arg("1")
-            .arg("--font-format=woff")
-            .arg(&format!("--zoom={}", zoom))
-            .arg(&dvi_path)
-            .output()
-            .expect("Couldn't run svisvgm properly!");
-
-        let buf = String::from_utf8_lossy(&cmd.stderr);
-        if !cmd.status.success() || buf.contains("error:") {
-            return Err(Error::InvalidDvisvgm(buf.to_string()));
-        }
+    let dvisvgm_path = which::which("dvisvgm")
+        .map_err(Error::BinaryNotFound)?;
+
+    let cmd = Command::new(dvisvgm_path)
+        .current_dir(dest_path)
+        .arg("-b")
+        .arg("1")
+        .arg("--font-format=woff")
+        .arg(format!("--zoom={}", zoom))
+        .arg(&dvi_path)
+        .output()
+        .expect("Couldn't run svisvgm properly!");
+
+    let buf = String::from_utf8_lossy(&cmd.stderr);
+    if !cmd.status.success() || buf.contains("error:") || !path.with_extension("svg").exists() {
+        return Err(Error::InvalidDvisvgm(buf.to_string()));
+    }
+
+    // links have to open in the page and not in the embedded fragment
+    let svg = fs::read_to_string(path.with_extension("svg")).map_err(Error::Io)?;
+    if svg.contains("<a ") {
+        fs::write(path.with_extension("svg"), svg.replace("<a ", "<a target=\"_top\" "))
+            .map_err(Error::Io)?;
     }
 
     Ok(())
 }
 
-/// Generate latex file from gnuplot
+/// Run gnuplot in the given directory with a header and the content piped to its stdin
 ///
-/// This function generates a latex file with gnuplot `epslatex` backend and then source it into
-/// the generate latex function
-fn generate_latex_from_gnuplot(dest_path: &Path, content: &str, filename: &str) -> Result<()> {
+/// Waits for gnuplot to exit, so that the output is complete once this function returns.
+fn run_gnuplot(dest_path: &Path, header: &str, content: &str) -> Result<()> {
     let gnuplot_path = which::which("gnuplot")
-        .map_err(|err| Error::BinaryNotFound(err))?;
+        .map_err(Error::BinaryNotFound)?;
 
-    let cmd = Command::new(gnuplot_path)
+    let mut cmd = Command::new(gnuplot_path)
         .stdin(Stdio::piped())
         .current_dir(dest_path)
         .arg("-p")
         .spawn()
-        .unwrap();
-    //.expect("Could not spawn gnuplot");
+        .expect("Could not spawn gnuplot");
 
-    let mut stdin = cmd.stdin.unwrap();
+    // close stdin before waiting, so that gnuplot sees the end of its input
+    let written = {
+        let mut stdin = cmd.stdin.take().unwrap();
 
-    stdin
-        .write_all(&format!("set output '{}.tex'\n", filename).as_bytes())
-        .map_err(|err| Error::Io(err))?;
-    stdin
-        .write_all("set terminal epslatex color standalone\n".as_bytes())
-        .map_err(|err| Error::Io(err))?;
-    stdin
-        .write_all(content.as_bytes())
-        .map_err(|err| Error::Io(err))?;
+        stdin.write_all(header.as_bytes())
+            .and_then(|_| stdin.write_all(content.as_bytes()))
+    };
+
+    let status = cmd.wait().map_err(Error::Io)?;
+    written.map_err(Error::Io)?;
+    if !status.success() {
+        return Err(Error::InvalidGnuplot(format!("gnuplot exited with {}", status)));
+    }
 
     Ok(())
 }
 
+/// Generate latex file from gnuplot
+///
+/// This function generates a latex file with gnuplot `epslatex` backend and then source it into
+/// the generate latex function
+fn generate_latex_from_gnuplot(dest_path: &Path, content: &str, filename: &str) -> Result<()> {
+    let header = format!("set output '{}.tex'\nset terminal epslatex color standalone\n", filename);
+
+    run_gnuplot(dest_path, &header, content)
+}
+
 /// Parse an equation with the given zoom
 pub fn parse_equation(
-    dest_path: &Path,
+    cache: &Cache,
     content: &str,
     zoom: f32,
 ) -> Result<String> {
-    let name = hash(content);
-    let path = dest_path.join(&name);
+    let name = fragment_name("equation", zoom, content);
 
-    // create a new tex file containing the equation
-    if !path.with_extension("tex").exists() {
-        let mut file = File::create(path.with_extension("tex")).map_err(|err| Error::Io(err))?;
+    cache.fetch(&name, |path| {
+        // create a new tex file containing the equation
+        let mut file = File::create(path.with_extension("tex")).map_err(Error::Io)?;
 
         file.write_all("\\documentclass[20pt, preview]{standalone}\n\\usepackage{amsmath}\\usepackage{amsfonts}\n\\begin{document}\n$$\n".as_bytes())
-            .map_err(|err| Error::Io(err))?;
+            .map_err(Error::Io)?;
 
         file.write_all(content.as_bytes())
-            .map_err(|err| Error::Io(err))?;
+            .map_err(Error::Io)?;
 
         file.write_all("$$\n\\end{document}".as_bytes())
-            .map_err(|err| Error::Io(err))?;
-    }
+            .map_err(Error::Io)?;
 
-    generate_svg_from_latex(&path, zoom)?;
+        generate_svg_from_latex(path, zoom)
+    })
+}
 
-    Ok(format!("{}.svg", name))
+/// Parse a display math environment like `align*` with the given zoom
+///
+/// Unlike `parse_equation` the content is not wrapped in `$$`, so that environments with several
+/// rows can be used. Numbers have to be set with `\tag` in the content.
+pub fn parse_environment(
+    cache: &Cache,
+    environment: &str,
+    content: &str,
+    zoom: f32,
+) -> Result<String> {
+    let name = fragment_name(&format!("environment {}", environment), zoom, content);
+
+    cache.fetch(&name, |path| {
+        let document = format!(
+            "\\documentclass[20pt, preview]{{standalone}}\n\\usepackage{{amsmath}}\\usepackage{{amsfonts}}\n\\begin{{document}}\n\\begin{{{0}}}\n{1}\\end{{{0}}}\n\\end{{document}}",
+            environment, content);
+
+        fs::write(path.with_extension("tex"), document)
+            .map_err(Error::Io)?;
+
+        generate_svg_from_latex(path, zoom)
+    })
 }
 
 /// Parse a latex content and convert it to a SVG file
 pub fn parse_latex(
-    dest_path: &Path,
+    cache: &Cache,
     content: &str,
 ) -> Result<String> {
-    let name = hash(content);
-    let path = dest_path.join(&name);
+    let name = fragment_name("latex", 1.0, content);
 
-    // create a new tex file containing the equation
-    if !path.with_extension("tex").exists() {
-        let mut file = File::create(path.with_extension("tex")).map_err(|err| Error::Io(err))?;
+    cache.fetch(&name, |path| {
+        // create a new tex file containing the figure
+        fs::write(path.with_extension("tex"), content)
+            .map_err(Error::Io)?;
 
-        file.write_all(content.as_bytes())
-            .map_err(|err| Error::Io(err))?;
-    }
-
-    generate_svg_from_latex(&path, 1.0)?;
-
-    Ok(format!("{}.svg", name))
+        generate_svg_from_latex(path, 1.0)
+    })
 }
 
 /// Parse a gnuplot file and generate a SVG file
 pub fn parse_gnuplot(
-    dest_path: &Path,
+    cache: &Cache,
     content: &str,
 ) -> Result<String> {
-    let name = hash(content);
-    let path = dest_path.join(&name);
+    let name = fragment_name("gnuplot", 1.0, content);
 
-    if !path.with_extension("tex").exists() {
-        //let name_plot = format!("{}_plot", name);
-        generate_latex_from_gnuplot(dest_path, content, &name)?;
-    }
+    cache.fetch(&name, |path| {
+        generate_latex_from_gnuplot(path.parent().unwrap(), content, &name)?;
 
-    if !path.with_extension("svg").exists() {
-        generate_svg_from_latex(&path, 1.0)?;
-    }
-
-    Ok(format!("{}.svg", name))
+        generate_svg_from_latex(path, 1.0)
+    })
 }
 
 /// Parse gnuplot without using the latex backend
 pub fn parse_gnuplot_only(
-    dest_path: &Path,
+    cache: &Cache,
     content: &str,
 ) -> Result<String> {
-    let name = hash(content);
-    let path = dest_path.join(&name);
-
-    if !path.with_extension("svg").exists() {
-        let gnuplot_path = which::which("gnuplot")
-            .map_err(|err| Error::BinaryNotFound(err))?;
-        let cmd = Command::new(gnuplot_path)
-            .stdin(Stdio::piped())
-            .current_dir(dest_path)
-            .arg("-p")
-            .spawn()
-            .unwrap();
-        //.expect("Could not spawn gnuplot");
-
-        let mut stdin = cmd.stdin.unwrap();
-        stdin
-            .write_all(&format!("set output '{}.svg'\n", name).as_bytes())
-            .map_err(|err| Error::Io(err))?;
-        stdin
-            .write_all("set terminal svg\n".as_bytes())
-            .map_err(|err| Error::Io(err))?;
-        stdin
-            .write_all("set encoding utf8\n".as_bytes())
-            .map_err(|err| Error::Io(err))?;
-        stdin
-            .write_all(content.as_bytes())
-            .map_err(|err| Error::Io(err))?;
-    }
+    let name = fragment_name("gnuplotonly", 1.0, content);
 
-    Ok(format!("{}.svg", name))
-}
-
-/// Generate html from BibTeX file using `bib2xhtml`
-pub fn bib_to_html(source: &str, bib2xhtml: &str) -> Result<String> {
-    let source = fs::canonicalize(source).unwrap();
-    let bib2xhtml = Path::new(bib2xhtml);
-
-    //./bib2xhtml.pl -s alpha -u -U ~/Documents/Bachelor_thesis/literature.bib
-    let cmd = Command::new(bib2xhtml.join("./bib2xhtml.pl"))
-        .current_dir(bib2xhtml)
-        .args(&["-s", "alpha", "-u", "-U"])
-        .arg(source)
-        .output()
-        .expect("Could not spawn bib2xhtml");
+    cache.fetch(&name, |path| {
+        let header = format!("set output '{}.svg'\nset terminal svg\nset encoding utf8\n", name);
 
-    let buf = String::from_utf8_lossy(&cmd.stdout);
-
-    let err_str = String::from_utf8_lossy(&cmd.stderr);
-    if err_str.contains("error messages)") {
-        Err(Error::InvalidBibliography(err_str.to_string()))
-    } else {
-        let buf = buf.split("\n")
-            .skip_while(|x| *x != "<dl class=\"bib2xhtml\">")
-            .take_while(|x| *x != "</dl>")
-            .map(|x| x.replace("<a name=\"", "<a id=\""))
-            .collect();
-
-        Ok(buf)
-    }
+        run_gnuplot(path.parent().unwrap(), &header, content)
+    })
 }
 
 /*pub fn parse_code(params: Vec<String>, content: String, url: String) -> Result<String> {
@@ -1,19 +1,46 @@
+mod assets;
+mod bibliography;
+mod cache;
+mod csl;
 mod error;
 mod fragments;
+mod locale;
+mod numbering;
 mod preprocess;
+mod references;
+mod theorems;
 
 use std::collections::HashMap;
 use std::path::Path;
-use std::fs;
 use std::path::PathBuf;
 
+use log::warn;
 use mdbook::book::{Book, BookItem, Chapter};
 use mdbook::errors::Error;
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
-use nom_bibtex::*;
 
-use preprocess::{replace_blocks, replace_inline_blocks};
+use assets::Assets;
+use bibliography::{Bibliography, Order, Style};
+use cache::{Cache, SharedStore};
+use locale::Locale;
+use numbering::Numbering;
+use preprocess::{collect_citations, collect_sections, replace_blocks, replace_inline_blocks, State};
+use references::{Kind, Reference, References};
+use theorems::Theorems;
 
+/// Visit all chapters in book order, a chapter before its sub-chapters
+///
+/// In contrast to `Book::for_each_mut` this keeps the order in which numbers are assigned.
+fn for_each_chapter_mut<F: FnMut(&mut Chapter)>(items: &mut Vec<BookItem>, func: &mut F) {
+    for item in items {
+        if let BookItem::Chapter(ch) = item {
+            func(ch);
+            for_each_chapter_mut(&mut ch.sub_items, func);
+        }
+    }
+}
+
+#[derive(Default)]
 pub struct Scientific;
 
 impl Scientific {
@@ -34,75 +61,213 @@ impl Preprocessor for Scientific {
                 .map(|x| x.as_str().unwrap())
                 .unwrap_or("fragments/");
 
-            let fragment_path = Path::new(fragment_path).canonicalize().unwrap();
+            // optionally share rendered fragments with other books of this user
+            let shared = if cfg.get("shared_cache").and_then(|x| x.as_bool()).unwrap_or(false) {
+                let limit = cfg.get("shared_cache_limit").and_then(|x| x.as_integer()).unwrap_or(512);
+
+                match SharedStore::open(limit.max(0) as u64 * 1024 * 1024) {
+                    Ok(x) => Some(x),
+                    Err(err) => return Err(format!("Could not open shared fragment cache: {}", err).into())
+                }
+            } else {
+                None
+            };
+
+            // lock the fragment cache for the whole build
+            let cache = match Cache::open(Path::new(fragment_path), shared) {
+                Ok(x) => x,
+                Err(err) => return Err(format!("Could not open fragment cache: {}", err).into())
+            };
+
+            // strings in the language of the book, like the names of figures
+            let environments: Vec<&str> = cfg.get("theorems").and_then(|x| x.as_table())
+                .map(|x| x.keys().map(|x| x.as_str()).collect())
+                .unwrap_or_default();
+            let mut locale = match Locale::from_config(ctx.config.book.language.as_deref(), cfg.get("locale"), &environments) {
+                Ok(x) => x,
+                Err(err) => return Err(format!("{}", err).into())
+            };
 
-            // track which fragments we use to copy them into the assets folder
-            let mut used_fragments = Vec::new();
             // track which references are created
-            let mut references = HashMap::new();
+            let references = References::new();
             // if there occurs an error skip everything and return the error
             let mut error = None;
 
-            // load all references in the bibliography and export to html
-            if let (Some(bib), Some(bib2xhtml)) = (cfg.get("bibliography"), cfg.get("bib2xhtml")) {
+            // load the bibliography, its entries are labelled once all citations are known
+            let mut bibliography = None;
+            if let Some(bib) = cfg.get("bibliography") {
                 let bib = bib.as_str().unwrap();
-                let bib2xhtml = bib2xhtml.as_str().unwrap();
+
+                if cfg.get("bib2xhtml").is_some() {
+                    warn!("`bib2xhtml` is not used anymore, the bibliography is formatted by `bibliography_style`");
+                }
 
                 if !Path::new(bib).exists() {
                     return Err(format!("bibliography {:?} not found!", bib).into());
                 }
 
-                // read entries in bibtex file
-                let bibtex = fs::read_to_string(bib).unwrap();
-                let bibtex = Bibtex::parse(&bibtex).unwrap();
-                for (i, entry) in bibtex.bibliographies().into_iter().enumerate() {
-                    references.insert(entry.citation_key().to_string(), format!("[{}]", i+1));
-                }
-                //
-                // create bibliography
-                let content = match fragments::bib_to_html(&bib, &bib2xhtml) {
+                let order = match Order::from_config(cfg.get("bibliography_order")) {
                     Ok(x) => x,
-                    Err(err) => return Err(format!("{:?}", err).into())
+                    Err(err) => return Err(format!("{}", err).into())
+                };
+
+                // only cited entries and those listed in `nocite`, like `\nocite` in LaTeX
+                let only_cited = cfg.get("bibliography_cited_only").and_then(|x| x.as_bool()).unwrap_or(false);
+                let nocite = match cfg.get("nocite") {
+                    Some(toml::Value::String(key)) => vec![key.clone()],
+                    Some(toml::Value::Array(keys)) => keys.iter().filter_map(|x| x.as_str()).map(|x| x.to_string()).collect(),
+                    Some(_) => return Err("`nocite` has to be a list of citation keys or `*`".into()),
+                    None => Vec::new(),
+                };
+
+                let loaded = match Style::from_config(cfg.get("bibliography_style"), &locale)
+                    .and_then(|style| Bibliography::from_file(Path::new(bib), style, &locale)) {
+                    Ok(x) => x,
+                    Err(err) => return Err(format!("{}", err).into())
+                };
+
+                // a citation style replaces the brackets around citations
+                if let Some((prefix, suffix)) = loaded.citation_affixes() {
+                    locale.set("citation", format!("{}{{}}{}", prefix, suffix));
+                }
+                if let Some(delimiter) = loaded.citation_delimiter() {
+                    locale.set("citation_delimiter", delimiter);
+                }
+
+                // add final chapter for bibliography, the entries are added after the blocks are processed
+                let name = locale.get("bibliography").unwrap_or("Bibliography");
+                book.push_item(Chapter::new(name, format!("# {}\n", name), PathBuf::from("bibliography.md"), Vec::new()));
+
+                bibliography = Some((loaded, bib.to_string(), order, only_cited, nocite));
+            }
+
+            // chapters with lists of figures, tables or equations in front of the book
+            if let Some(lists) = cfg.get("lists") {
+                let lists = match lists.as_array() {
+                    Some(x) => x,
+                    None => return Err("`lists` has to be an array like `[\"figures\", \"tables\"]`".into())
                 };
 
-                // add final chapter for bibliography
-                let bib_chapter = Chapter::new("Bibliography", format!("# Bibliography\n{}", content), PathBuf::from("bibliography.md"), Vec::new());
-                book.push_item(bib_chapter);
+                for list in lists.iter().rev() {
+                    let list = match list.as_str() {
+                        Some(x) if ["figures", "tables", "equations"].contains(&x) => x,
+                        _ => return Err(format!("unknown list {}, expected `figures`, `tables` or `equations`", list).into())
+                    };
+
+                    let name = locale.get(&format!("list_of_{}", list)).unwrap_or(list);
+                    let content = format!("# {}\n\n{{{{#list-of-{}}}}}\n", name, list);
+                    book.sections.insert(0, BookItem::Chapter(Chapter::new(name, content, PathBuf::from(format!("list-of-{}.md", list)), Vec::new())));
+                }
             }
 
             // assets path
             let asset_path = cfg.get("assets").map(|x| x.as_str().unwrap()).unwrap_or("src/");
             let asset_path = ctx.root.join(asset_path);
 
-            // process blocks like `$$ .. $$`
-            book.for_each_mut(|item| {
+            // either copy fragments to the book source or embed them into the markup
+            let assets = match Assets::from_config(
+                cfg.get("assets_mode").and_then(|x| x.as_str()),
+                cfg.get("assets_dir").and_then(|x| x.as_str()),
+            ) {
+                Ok(x) => x,
+                Err(err) => return Err(format!("{}", err).into())
+            };
+
+            // numbering schemes of figures and equations
+            let chapters = book.iter().filter_map(|item| match item {
+                BookItem::Chapter(ch) => Some(ch),
+                _ => None,
+            });
+            let numbering = match Numbering::from_config(cfg.get("numbering")).and_then(|x| x.check(chapters).map(|_| x)) {
+                Ok(x) => x,
+                Err(err) => return Err(format!("{}", err).into())
+            };
+
+            // theorem-like environments like `$$theorem, <name>, <title>`
+            let theorems = match Theorems::from_config(cfg.get("theorems"), &locale) {
+                Ok(x) => x,
+                Err(err) => return Err(format!("{}", err).into())
+            };
+
+            let mut state = State {
+                cache,
+                assets,
+                asset_path,
+                used_fragments: Vec::new(),
+                references,
+                numbering,
+                theorems,
+                deferred: Vec::new(),
+                entries: Vec::new(),
+                authors: HashMap::new(),
+                locale,
+            };
+
+            // collect chapters and headings and process blocks like `$$ .. $$`
+            for_each_chapter_mut(&mut book.sections, &mut |ch| {
                 if error.is_some() {
                     return;
                 }
 
-                if let BookItem::Chapter(ref mut ch) = item {
-                    let head_number = ch.number.as_ref().map(|x| format!("{}", x)).unwrap_or("".into());
+                let head_number = ch.number.as_ref().map(|x| format!("{}", x)).unwrap_or("".into());
 
-                    match replace_blocks(&fragment_path, &asset_path, &ch.content, &head_number, &mut used_fragments, &mut references) {
-                        Ok(x) => ch.content = x,
-                        Err(err) => error = Some(format!("Error in chapter {} {:?}", head_number, err))
-                    }
+                match collect_sections(&mut state, ch).and_then(|_| replace_blocks(&mut state, ch)) {
+                    Ok(x) => ch.content = x,
+                    Err(err) => error = Some(format!("Error in chapter {}: {}", head_number, err))
                 }
             });
 
+            // label the entries of the bibliography, which is the only source of the labels shown in
+            // citations and in the bibliography chapter
+            if let (Some((mut bibliography, bib, order, only_cited, nocite)), None) = (bibliography, &error) {
+                // keys cited in the book, in the order of their first citation
+                let mut cited = Vec::new();
+                for_each_chapter_mut(&mut book.sections, &mut |ch| {
+                    for key in collect_citations(&state, ch) {
+                        if !cited.contains(&key) {
+                            cited.push(key);
+                        }
+                    }
+                });
+
+                if let Err(err) = bibliography.arrange(order, &cited, only_cited, &nocite) {
+                    return Err(format!("{}", err).into());
+                }
+
+                for entry in bibliography.entries() {
+                    state.authors.insert(entry.key.clone(), bibliography.author_year(entry, &state.locale));
+
+                    let res = state.references.insert(Kind::Bibliography, &entry.key, Reference {
+                        name: String::new(),
+                        key: String::new(),
+                        number: entry.label.clone(),
+                        title: None,
+                        chapter: PathBuf::from("bibliography.md"),
+                        site: bib.clone(),
+                    });
+
+                    if let Err(err) = res {
+                        return Err(format!("{}", err).into());
+                    }
+                }
+
+                let html = bibliography.to_html(&state.locale);
+                for_each_chapter_mut(&mut book.sections, &mut |ch| if ch.path == Path::new("bibliography.md") {
+                    ch.content.push_str(&html);
+                });
+            }
+
             // process inline blocks like `$ .. $`
-            book.for_each_mut(|item| {
+            for_each_chapter_mut(&mut book.sections, &mut |ch| {
                 if error.is_some() {
                     return;
                 }
 
-                if let BookItem::Chapter(ref mut ch) = item {
-                    let head_number = ch.number.as_ref().map(|x| format!("{}", x)).unwrap_or("".into());
+                let head_number = ch.number.as_ref().map(|x| format!("{}", x)).unwrap_or("".into());
 
-                    match replace_inline_blocks(&fragment_path, &ch.content, &references, &mut used_fragments) {
-                        Ok(x) => ch.content = x,
-                        Err(err) => error = Some(format!("Error in chapter {}: {:?}", head_number, err))
-                    }
+                match replace_inline_blocks(&mut state, ch) {
+                    Ok(x) => ch.content = x,
+                    Err(err) => error = Some(format!("Error in chapter {}: {}", head_number, err))
                 }
             });
 
@@ -110,15 +275,9 @@ impl Preprocessor for Scientific {
                 return Err(err.into());
             }
 
-            // the output path is `src/assets`, which get copied to the output directory
-            let dest = ctx.root.join("src").join("assets");
-            if !dest.exists() {
-                fs::create_dir_all(&dest).unwrap();
-            }
-
-            // copy all fragments
-            for fragment in used_fragments {
-                fs::copy(fragment_path.join(&fragment), dest.join(&fragment)).unwrap();
+            // copy all fragments to the source directory, from where they get copied to the output
+            if let Err(err) = state.assets.publish(&state.cache, &ctx.root.join(&ctx.config.book.src), &state.used_fragments) {
+                return Err(format!("Could not publish fragments: {}", err).into());
             }
 
             Ok(book)
@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+
+use log::warn;
+
+use crate::error::{Error, Result};
+use crate::references::Kind;
+
+/// Built-in strings of each language, missing keys fall back to English
+///
+/// Names of objects have a plural with the suffix `_plural` and may have a spelled out form for the
+/// start of a sentence with the suffix `_start`.
+const LANGUAGES: [(&str, &[(&str, &str)]); 4] = [
+    ("en", &[
+        ("figure", "Figure"), ("figure_plural", "Figures"),
+        ("table", "Table"), ("table_plural", "Tables"),
+        ("equation", "Eq."), ("equation_plural", "Eqs."),
+        ("equation_start", "Equation"), ("equation_start_plural", "Equations"),
+        ("section", "Section"), ("section_plural", "Sections"),
+        ("chapter", "Chapter"), ("chapter_plural", "Chapters"),
+        ("definition", "Definition"), ("definition_plural", "Definitions"),
+        ("theorem", "Theorem"), ("theorem_plural", "Theorems"),
+        ("lemma", "Lemma"), ("lemma_plural", "Lemmas"),
+        ("corollary", "Corollary"), ("corollary_plural", "Corollaries"),
+        ("proof", "Proof"), ("proof_plural", "Proofs"),
+        ("and", "and"),
+        ("bibliography", "Bibliography"),
+        ("in", "In {}"), ("edition", "{} edition"), ("page", "p. {}"), ("pages", "pp. {}"),
+        ("phd_thesis", "PhD thesis"), ("masters_thesis", "Master's thesis"),
+        ("report", "Technical report"), ("report_number", "Technical Report {}"),
+        ("list_of_figures", "List of Figures"), ("list_of_tables", "List of Tables"),
+        ("list_of_equations", "List of Equations"),
+        ("citation", "[{}]"), ("citation_delimiter", ", "),
+        ("et_al", "et al."),
+    ]),
+    ("de", &[
+        ("figure", "Abbildung"), ("figure_plural", "Abbildungen"),
+        ("table", "Tabelle"), ("table_plural", "Tabellen"),
+        ("equation", "Gl."), ("equation_plural", "Gln."),
+        ("equation_start", "Gleichung"), ("equation_start_plural", "Gleichungen"),
+        ("section", "Abschnitt"), ("section_plural", "Abschnitte"),
+        ("chapter", "Kapitel"), ("chapter_plural", "Kapitel"),
+        ("definition", "Definition"), ("definition_plural", "Definitionen"),
+        ("theorem", "Satz"), ("theorem_plural", "Sätze"),
+        ("lemma", "Lemma"), ("lemma_plural", "Lemmata"),
+        ("corollary", "Korollar"), ("corollary_plural", "Korollare"),
+        ("proof", "Beweis"), ("proof_plural", "Beweise"),
+        ("and", "und"),
+        ("bibliography", "Literaturverzeichnis"),
+        ("in", "In: {}"), ("edition", "{} Auflage"), ("page", "S. {}"), ("pages", "S. {}"),
+        ("phd_thesis", "Dissertation"), ("masters_thesis", "Masterarbeit"),
+        ("report", "Technischer Bericht"), ("report_number", "Technischer Bericht {}"),
+        ("list_of_figures", "Abbildungsverzeichnis"), ("list_of_tables", "Tabellenverzeichnis"),
+        ("list_of_equations", "Gleichungsverzeichnis"),
+    ]),
+    ("es", &[
+        ("figure", "Figura"), ("figure_plural", "Figuras"),
+        ("table", "Tabla"), ("table_plural", "Tablas"),
+        ("equation", "Ec."), ("equation_plural", "Ecs."),
+        ("equation_start", "Ecuación"), ("equation_start_plural", "Ecuaciones"),
+        ("section", "Sección"), ("section_plural", "Secciones"),
+        ("chapter", "Capítulo"), ("chapter_plural", "Capítulos"),
+        ("definition", "Definición"), ("definition_plural", "Definiciones"),
+        ("theorem", "Teorema"), ("theorem_plural", "Teoremas"),
+        ("lemma", "Lema"), ("lemma_plural", "Lemas"),
+        ("corollary", "Corolario"), ("corollary_plural", "Corolarios"),
+        ("proof", "Demostración"), ("proof_plural", "Demostraciones"),
+        ("and", "y"),
+        ("bibliography", "Bibliografía"),
+        ("in", "En {}"), ("edition", "{} edición"), ("page", "p. {}"), ("pages", "pp. {}"),
+        ("phd_thesis", "Tesis doctoral"), ("masters_thesis", "Tesis de maestría"),
+        ("report", "Informe técnico"), ("report_number", "Informe técnico {}"),
+        ("list_of_figures", "Índice de figuras"), ("list_of_tables", "Índice de tablas"),
+        ("list_of_equations", "Índice de ecuaciones"),
+    ]),
+    ("fr", &[
+        ("figure", "Figure"), ("figure_plural", "Figures"),
+        ("table", "Tableau"), ("table_plural", "Tableaux"),
+        ("equation", "Éq."), ("equation_plural", "Éqs."),
+        ("equation_start", "Équation"), ("equation_start_plural", "Équations"),
+        ("section", "Section"), ("section_plural", "Sections"),
+        ("chapter", "Chapitre"), ("chapter_plural", "Chapitres"),
+        ("definition", "Définition"), ("definition_plural", "Définitions"),
+        ("theorem", "Théorème"), ("theorem_plural", "Théorèmes"),
+        ("lemma", "Lemme"), ("lemma_plural", "Lemmes"),
+        ("corollary", "Corollaire"), ("corollary_plural", "Corollaires"),
+        ("proof", "Démonstration"), ("proof_plural", "Démonstrations"),
+        ("and", "et"),
+        ("bibliography", "Bibliographie"),
+        ("in", "Dans {}"), ("edition", "{} édition"), ("page", "p. {}"), ("pages", "pp. {}"),
+        ("phd_thesis", "Thèse de doctorat"), ("masters_thesis", "Mémoire de master"),
+        ("report", "Rapport technique"), ("report_number", "Rapport technique {}"),
+        ("list_of_figures", "Table des figures"), ("list_of_tables", "Liste des tableaux"),
+        ("list_of_equations", "Liste des équations"),
+    ]),
+];
+
+/// Strings displayed in the generated markup, in the language of the book
+pub struct Locale {
+    language: String,
+    strings: HashMap<String, String>,
+}
+
+impl Locale {
+    /// Select the strings of a language and apply the overrides of the `locale` table
+    ///
+    /// The language is taken from `locale.language` or else from `book.language`. Regional
+    /// variants like `de-AT` use the strings of their base language.
+    ///
+    /// ```toml
+    /// [preprocessor.scientific.locale]
+    /// language = "de"
+    /// figure = "Abb."
+    /// figure_plural = "Abb."
+    /// ```
+    ///
+    /// Besides the built-in keys the names of the custom `environments` can be given.
+    pub fn from_config(language: Option<&str>, cfg: Option<&toml::Value>, environments: &[&str]) -> Result<Locale> {
+        let cfg = match cfg {
+            Some(cfg) => Some(cfg.as_table()
+                .ok_or_else(|| Error::InvalidConfig("`locale` has to be a table".into()))?),
+            None => None
+        };
+
+        let language = match cfg.and_then(|x| x.get("language")) {
+            Some(language) => Some(language.as_str()
+                .ok_or_else(|| Error::InvalidConfig("`locale.language` has to be a string".into()))?),
+            None => language
+        };
+        let language = language.unwrap_or("en").split(['-', '_']).next().unwrap().to_lowercase();
+
+        let mut strings: HashMap<String, String> = LANGUAGES[0].1.iter()
+            .map(|(key, val)| (key.to_string(), val.to_string()))
+            .collect();
+
+        match LANGUAGES.iter().find(|(x, _)| *x == language) {
+            Some((_, table)) => strings.extend(table.iter().map(|(key, val)| (key.to_string(), val.to_string()))),
+            None => warn!("No strings for language `{}`, using English", language),
+        }
+
+        for (key, val) in cfg.into_iter().flatten().filter(|(key, _)| *key != "language") {
+            let name = ["_start_plural", "_plural", "_start"].iter()
+                .find_map(|suffix| key.strip_suffix(suffix))
+                .unwrap_or(key);
+            let known = strings.contains_key(key.as_str()) || strings.contains_key(&format!("{}_plural", name))
+                || environments.contains(&name);
+
+            if !known {
+                return Err(Error::InvalidConfig(format!("`locale.{}` is not a known string", key)));
+            }
+
+            let val = val.as_str()
+                .ok_or_else(|| Error::InvalidConfig(format!("`locale.{}` has to be a string", key)))?;
+
+            strings.insert(key.clone(), val.to_string());
+        }
+
+        if !strings["citation"].contains("{}") {
+            return Err(Error::InvalidConfig("`locale.citation` has to contain `{}` for the numbers".into()));
+        }
+
+        for key in ["in", "edition", "page", "pages", "report_number"] {
+            if !strings[key].contains("{}") {
+                return Err(Error::InvalidConfig(format!("`locale.{}` has to contain `{{}}` for the value", key)));
+            }
+        }
+
+        Ok(Locale { language, strings })
+    }
+
+    /// Look up a string by its key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.strings.get(key).map(|x| x.as_str())
+    }
+
+    /// Name displayed in front of the number of an object, for example `Figure`
+    pub fn name(&self, kind: Kind) -> &str {
+        match kind.key() {
+            "" => "",
+            key => &self.strings[key],
+        }
+    }
+
+    /// Variant of a displayed name with its key, for example `figure_plural`
+    ///
+    /// Names which differ from the string of their key, like renamed environments, have no variants.
+    fn variant(&self, key: &str, name: &str, suffix: &str) -> Option<&str> {
+        match self.get(key) {
+            Some(x) if x == name => self.get(&format!("{}{}", key, suffix)),
+            _ => None,
+        }
+    }
+
+    /// Plural of a displayed name with its key in the locale
+    ///
+    /// Names without a plural in the locale get an `s` appended in English and are kept otherwise.
+    pub fn plural(&self, key: &str, name: &str) -> String {
+        match self.variant(key, name, "_plural") {
+            Some(plural) => plural.to_string(),
+            None if self.language == "en" && !name.is_empty() => format!("{}s", name),
+            None => name.to_string(),
+        }
+    }
+
+    /// Displayed name at the start of a sentence, with abbreviations spelled out
+    pub fn capitalized(&self, key: &str, name: &str, plural: bool) -> String {
+        let suffix = if plural { "_start_plural" } else { "_start" };
+
+        let name = match self.variant(key, name, suffix) {
+            Some(name) => name.to_string(),
+            None if plural => self.plural(key, name),
+            None => name.to_string(),
+        };
+
+        let mut chars = name.chars();
+        chars.next().map(|x| x.to_uppercase().chain(chars).collect()).unwrap_or_default()
+    }
+
+    /// Join the items of an enumeration like `a, b and c`
+    pub fn enumeration(&self, items: &[String]) -> String {
+        match items {
+            [] => String::new(),
+            [item] => item.clone(),
+            [init @ .., last] => format!("{} {} {}", init.join(", "), self.strings["and"], last),
+        }
+    }
+
+    /// Replace a string, for example the citation format given by a citation style
+    pub fn set(&mut self, key: &str, value: String) {
+        self.strings.insert(key.to_string(), value);
+    }
+
+    /// Insert a value into a string with a placeholder, for example `pp. {}` for pages
+    pub fn fill(&self, key: &str, value: &str) -> String {
+        self.strings[key].replace("{}", value)
+    }
+
+    /// Format the numbers of a citation, for example `[1, 3]`
+    pub fn citation(&self, numbers: &str) -> String {
+        self.strings["citation"].replace("{}", numbers)
+    }
+
+    /// Format a citation of several entries, separated by the delimiter of citations
+    pub fn citations(&self, items: &[String]) -> String {
+        self.citation(&items.join(&self.strings["citation_delimiter"]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locale(language: Option<&str>, cfg: &str) -> Result<Locale> {
+        Locale::from_config(language, Some(&cfg.parse::<toml::Value>().unwrap()), &["remark"])
+    }
+
+    #[test]
+    fn languages() {
+        let locale = locale(Some("de-AT"), "").unwrap();
+        assert_eq!(locale.name(Kind::Figure), "Abbildung");
+        assert_eq!(locale.get("et_al"), Some("et al."));
+        assert_eq!(locale.enumeration(&["a".into(), "b".into(), "c".into()]), "a, b und c");
+    }
+
+    #[test]
+    fn overrides() {
+        let locale = locale(Some("de"), "language = 'en'\nfigure = 'Fig.'\nremark = 'Note'\nremark_plural = 'Notes'").unwrap();
+        assert_eq!(locale.name(Kind::Figure), "Fig.");
+        assert_eq!(locale.name(Kind::Table), "Table");
+        assert_eq!(locale.plural("remark", "Note"), "Notes");
+
+        assert!(locale_err("figur = 'Abb.'"));
+        assert!(locale_err("and_plural = 'ands'"));
+        assert!(locale_err("figure = 1"));
+        assert!(locale_err("citation = 'cite'"));
+    }
+
+    fn locale_err(cfg: &str) -> bool {
+        matches!(locale(None, cfg), Err(Error::InvalidConfig(_)))
+    }
+
+    #[test]
+    fn variants() {
+        let locale = locale(None, "").unwrap();
+        assert_eq!(locale.plural("figure", "Figure"), "Figures");
+        assert_eq!(locale.plural("corollary", "Corollary"), "Corollaries");
+        assert_eq!(locale.plural("theorem", "Proposition"), "Propositions");
+        assert_eq!(locale.capitalized("equation", "Eq.", false), "Equation");
+        assert_eq!(locale.capitalized("equation", "Eq.", true), "Equations");
+        assert_eq!(locale.capitalized("section", "section", false), "Section");
+
+        let locale = Locale::from_config(Some("fr"), None, &[]).unwrap();
+        assert_eq!(locale.plural("theorem", "Théorème"), "Théorèmes");
+        assert_eq!(locale.plural("remark", "Remarque"), "Remarque");
+    }
+}
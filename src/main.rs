@@ -16,6 +16,9 @@ pub fn make_app() -> App<'static, 'static> {
 }
 
 fn main() {
+    // diagnostics are printed like the ones of mdBook itself
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
     let matches = make_app().get_matches();
 
     // Users will want to construct their own preprocessor here
@@ -35,8 +38,8 @@ fn handle_preprocessing(pre: &dyn Preprocessor) -> Result<(), Error> {
     if ctx.mdbook_version != mdbook::MDBOOK_VERSION {
         // We should probably use the `semver` crate to check compatibility
         // here...
-        eprintln!(
-            "Warning: The {} plugin was built against version {} of mdbook, \
+        log::warn!(
+            "The {} plugin was built against version {} of mdbook, \
              but we're being called from version {}",
             pre.name(),
             mdbook::MDBOOK_VERSION,
@@ -53,7 +56,7 @@ fn handle_preprocessing(pre: &dyn Preprocessor) -> Result<(), Error> {
 
 fn handle_supports(pre: &dyn Preprocessor, sub_args: &ArgMatches) -> ! {
     let renderer = sub_args.value_of("renderer").expect("Required argument");
-    let supported = pre.supports_renderer(&renderer);
+    let supported = pre.supports_renderer(renderer);
 
     // Signal whether the renderer is supported by exiting with 1 or 0.
     if supported {
@@ -0,0 +1,360 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use mdbook::book::Chapter;
+
+use crate::error::{Error, Result};
+use crate::references::Kind;
+
+/// Style of the counter within a chapter or section
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Style {
+    Arabic,
+    Roman,
+    UpperRoman,
+    Alphabetic,
+    UpperAlphabetic,
+}
+
+impl Style {
+    fn from_str(style: &str) -> Option<Style> {
+        match style {
+            "arabic" => Some(Style::Arabic),
+            "roman" => Some(Style::Roman),
+            "Roman" => Some(Style::UpperRoman),
+            "alphabetic" => Some(Style::Alphabetic),
+            "Alphabetic" => Some(Style::UpperAlphabetic),
+            _ => None
+        }
+    }
+
+    /// Format a counter, which starts at one
+    pub fn format(&self, mut num: u32) -> String {
+        match self {
+            Style::Arabic => num.to_string(),
+            Style::Roman | Style::UpperRoman => {
+                const NUMERALS: [(u32, &str); 13] = [
+                    (1000, "m"), (900, "cm"), (500, "d"), (400, "cd"), (100, "c"), (90, "xc"),
+                    (50, "l"), (40, "xl"), (10, "x"), (9, "ix"), (5, "v"), (4, "iv"), (1, "i"),
+                ];
+
+                let mut out = String::new();
+                for (val, numeral) in NUMERALS.iter() {
+                    while num >= *val {
+                        out.push_str(numeral);
+                        num -= val;
+                    }
+                }
+
+                if *self == Style::UpperRoman {
+                    out.to_uppercase()
+                } else {
+                    out
+                }
+            },
+            Style::Alphabetic | Style::UpperAlphabetic => {
+                let base = if *self == Style::UpperAlphabetic { b'A' } else { b'a' };
+
+                // bijective base 26, so that `z` is followed by `aa`
+                let mut out = Vec::new();
+                while num > 0 {
+                    num -= 1;
+                    out.push((base + (num % 26) as u8) as char);
+                    num /= 26;
+                }
+
+                out.iter().rev().collect()
+            }
+        }
+    }
+}
+
+/// Numbering scheme of one kind of objects
+#[derive(Clone, Debug)]
+pub struct Scheme {
+    /// Number of section levels prefixed to the counter, `None` for all levels
+    depth: Option<usize>,
+    /// Separator between section numbers and the counter
+    separator: String,
+    /// Style of the counter
+    style: Style,
+}
+
+impl Default for Scheme {
+    fn default() -> Scheme {
+        Scheme {
+            depth: None,
+            separator: ".".into(),
+            style: Style::Arabic,
+        }
+    }
+}
+
+impl Scheme {
+    /// Parse a scheme from a table like `{ within = "section", depth = 2, style = "roman" }`
+    fn from_config(kind: &str, cfg: &toml::value::Table) -> Result<Scheme> {
+        let mut scheme = Scheme::default();
+        let get_str = |key: &str| -> Result<Option<&str>> {
+            match cfg.get(key) {
+                Some(x) => x.as_str()
+                    .map(Some)
+                    .ok_or_else(|| Error::InvalidConfig(format!("`numbering.{}.{}` has to be a string", kind, key))),
+                None => Ok(None)
+            }
+        };
+
+        let depth = match cfg.get("depth") {
+            Some(depth) => match depth.as_integer() {
+                Some(depth) if depth > 0 => Some(depth as usize),
+                _ => return Err(Error::InvalidConfig(format!("`numbering.{}.depth` has to be a positive integer", kind))),
+            },
+            None => None
+        };
+
+        scheme.depth = match get_str("within")? {
+            Some("book") => Some(0),
+            Some("chapter") => Some(1),
+            Some("section") | None => depth,
+            Some(x) => return Err(Error::InvalidConfig(format!("`numbering.{}.within` is `{}`, expected `book`, `chapter` or `section`", kind, x))),
+        };
+
+        if let Some(separator) = get_str("separator")? {
+            scheme.separator = separator.to_string();
+        }
+
+        if let Some(style) = get_str("style")? {
+            scheme.style = Style::from_str(style)
+                .ok_or_else(|| Error::InvalidConfig(format!("`numbering.{}.style` is `{}`, expected `arabic`, `roman`, `Roman`, `alphabetic` or `Alphabetic`", kind, style)))?;
+        }
+
+        Ok(scheme)
+    }
+}
+
+/// Counters of all numbered objects in the book
+///
+/// Each kind has a separate counter for every chapter prefix, so unnumbered chapters share one
+/// counter instead of restarting it. Chapters have to be processed in book order.
+#[derive(Default)]
+pub struct Numbering {
+    schemes: HashMap<Kind, Scheme>,
+    counters: HashMap<(Kind, String, Vec<String>), u32>,
+    /// First chapter of the appendix
+    appendix: Option<PathBuf>,
+    /// Top-level number of the first appendix chapter, once it was visited
+    appendix_start: Option<u32>,
+    /// Explicit prefixes of chapters, replacing their section number
+    prefixes: HashMap<PathBuf, String>,
+}
+
+impl Numbering {
+    /// Parse the `numbering` table, which contains a scheme for each kind
+    ///
+    /// ```toml
+    /// [preprocessor.scientific.numbering]
+    /// appendix = "appendix.md"
+    ///
+    /// [preprocessor.scientific.numbering.figure]
+    /// within = "chapter"
+    ///
+    /// [preprocessor.scientific.numbering.prefix]
+    /// "preface.md" = "P"
+    /// ```
+    pub fn from_config(cfg: Option<&toml::Value>) -> Result<Numbering> {
+        let mut numbering = Numbering::default();
+
+        let cfg = match cfg {
+            Some(cfg) => cfg.as_table()
+                .ok_or_else(|| Error::InvalidConfig("`numbering` has to be a table".into()))?,
+            None => return Ok(numbering)
+        };
+
+        for (key, val) in cfg {
+            let kind = match key.as_str() {
+                "figure" => Kind::Figure,
+                "equation" => Kind::Equation,
+                "table" => Kind::Table,
+                "theorem" => Kind::Theorem,
+                "appendix" => {
+                    let path = val.as_str()
+                        .ok_or_else(|| Error::InvalidConfig("`numbering.appendix` has to be the path of a chapter".into()))?;
+
+                    numbering.appendix = Some(PathBuf::from(path));
+                    continue;
+                },
+                "prefix" => {
+                    let prefixes = val.as_table()
+                        .ok_or_else(|| Error::InvalidConfig("`numbering.prefix` has to be a table of chapter paths".into()))?;
+
+                    for (path, prefix) in prefixes {
+                        let prefix = prefix.as_str()
+                            .ok_or_else(|| Error::InvalidConfig(format!("prefix of chapter `{}` has to be a string", path)))?;
+
+                        numbering.prefixes.insert(PathBuf::from(path), prefix.to_string());
+                    }
+                    continue;
+                },
+                _ => return Err(Error::InvalidConfig(format!("unknown key `{}` in `numbering`, expected `figure`, `equation`, `table`, `theorem`, `appendix` or `prefix`", key)))
+            };
+
+            let val = val.as_table()
+                .ok_or_else(|| Error::InvalidConfig(format!("`numbering.{}` has to be a table", key)))?;
+
+            numbering.schemes.insert(kind, Scheme::from_config(key, val)?);
+        }
+
+        Ok(numbering)
+    }
+
+    /// Check that the appendix and the prefixes refer to chapters of the book
+    ///
+    /// The appendix has to start at a numbered chapter, as its letters replace the chapter number.
+    pub fn check<'a>(&self, chapters: impl Iterator<Item = &'a Chapter>) -> Result<()> {
+        let chapters = chapters.collect::<Vec<_>>();
+        let find = |path: &PathBuf| chapters.iter().find(|ch| ch.path == *path);
+
+        if let Some(path) = &self.appendix {
+            match find(path) {
+                Some(ch) if ch.number.as_ref().map(|x| !x.0.is_empty()).unwrap_or(false) => {},
+                Some(_) => return Err(Error::InvalidConfig(format!("`numbering.appendix` is the unnumbered chapter `{}`", path.display()))),
+                None => return Err(Error::InvalidConfig(format!("`numbering.appendix` is `{}`, which is not a chapter of the book", path.display()))),
+            }
+        }
+
+        match self.prefixes.keys().find(|path| find(path).is_none()) {
+            Some(path) => Err(Error::InvalidConfig(format!("`numbering.prefix` contains `{}`, which is not a chapter of the book", path.display()))),
+            None => Ok(())
+        }
+    }
+
+    /// Return the number prefix of a chapter, one element per section level
+    ///
+    /// This is the explicit prefix of the chapter if configured and the section number otherwise,
+    /// with the top-level number of appendix chapters replaced by a letter. Unnumbered chapters
+    /// have an empty prefix.
+    pub fn chapter(&mut self, ch: &Chapter) -> Vec<String> {
+        if let Some(prefix) = self.prefixes.get(&ch.path) {
+            return vec![prefix.clone()];
+        }
+
+        let number = match &ch.number {
+            Some(number) if !number.0.is_empty() => &number.0,
+            _ => return Vec::new()
+        };
+
+        if self.appendix.as_ref() == Some(&ch.path) {
+            self.appendix_start = Some(number[0]);
+        }
+
+        number.iter().enumerate()
+            .map(|(i, x)| match self.appendix_start {
+                Some(start) if i == 0 && *x >= start => Style::UpperAlphabetic.format(x - start + 1),
+                _ => x.to_string()
+            })
+            .collect()
+    }
+
+    /// Return the number of the next object of a kind in a chapter with the given prefix
+    pub fn next(&mut self, kind: Kind, prefix: &[String]) -> String {
+        self.next_in(kind, kind.prefix(), prefix)
+    }
+
+    /// Return the next number of a named counter of a kind
+    ///
+    /// Objects of one kind can be numbered by several counters, like the theorem-like environments.
+    pub fn next_in(&mut self, kind: Kind, counter: &str, prefix: &[String]) -> String {
+        let scheme = self.schemes.entry(kind).or_default();
+
+        let mut prefix = prefix.to_vec();
+        if let Some(depth) = scheme.depth {
+            prefix.truncate(depth);
+        }
+
+        let counter = self.counters.entry((kind, counter.to_string(), prefix.clone())).or_insert(0);
+        *counter += 1;
+
+        prefix.into_iter()
+            .chain(Some(scheme.style.format(*counter)))
+            .collect::<Vec<_>>()
+            .join(&scheme.separator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mdbook::book::SectionNumber;
+
+    fn numbering(cfg: &str) -> Numbering {
+        Numbering::from_config(Some(&cfg.parse::<toml::Value>().unwrap())).unwrap()
+    }
+
+    fn prefix(elms: &[&str]) -> Vec<String> {
+        elms.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn chapter(path: &str, number: Option<Vec<u32>>) -> Chapter {
+        let mut ch = Chapter::new(path, String::new(), path, Vec::new());
+        ch.number = number.map(SectionNumber);
+
+        ch
+    }
+
+    #[test]
+    fn styles() {
+        let format = |style, nums: &[u32]| nums.iter().map(|x| Style::format(&style, *x)).collect::<Vec<_>>();
+
+        assert_eq!(format(Style::Arabic, &[1, 12]), ["1", "12"]);
+        assert_eq!(format(Style::Roman, &[1, 4, 9, 14, 40, 1994]), ["i", "iv", "ix", "xiv", "xl", "mcmxciv"]);
+        assert_eq!(format(Style::UpperRoman, &[3]), ["III"]);
+        assert_eq!(format(Style::Alphabetic, &[1, 26, 27, 52, 703]), ["a", "z", "aa", "az", "aaa"]);
+        assert_eq!(format(Style::UpperAlphabetic, &[2]), ["B"]);
+    }
+
+    #[test]
+    fn schemes() {
+        let mut numbering = numbering("
+            [figure]
+            within = \"chapter\"
+            separator = \"-\"
+            style = \"roman\"
+
+            [equation]
+            depth = 2
+        ");
+
+        let section = prefix(&["2", "3", "1"]);
+        assert_eq!(numbering.next(Kind::Figure, &section), "2-i");
+        assert_eq!(numbering.next(Kind::Figure, &section), "2-ii");
+        assert_eq!(numbering.next(Kind::Equation, &section), "2.3.1");
+        assert_eq!(numbering.next(Kind::Table, &section), "2.3.1.1");
+
+        // counters restart in every chapter and are shared by unnumbered chapters
+        assert_eq!(numbering.next(Kind::Figure, &prefix(&["3"])), "3-i");
+        assert_eq!(numbering.next(Kind::Table, &[]), "1");
+        assert_eq!(numbering.next(Kind::Table, &[]), "2");
+    }
+
+    #[test]
+    fn invalid_schemes() {
+        for cfg in &["[figure]\nwithin = \"part\"", "[figure]\nstyle = \"greek\"", "[figure]\ndepth = 0", "[figures]"] {
+            assert!(Numbering::from_config(Some(&cfg.parse::<toml::Value>().unwrap())).is_err(), "{}", cfg);
+        }
+    }
+
+    #[test]
+    fn appendix() {
+        let chapters = [chapter("intro.md", Some(vec![1])), chapter("app.md", Some(vec![2])), chapter("app2.md", Some(vec![3])), chapter("notes.md", None)];
+
+        let mut appendix = numbering("appendix = \"app.md\"");
+        appendix.check(chapters.iter()).unwrap();
+        let prefixes = chapters.iter().map(|ch| appendix.chapter(ch).join(".")).collect::<Vec<_>>();
+        assert_eq!(prefixes, ["1", "A", "B", ""]);
+
+        // the appendix and prefixes have to name chapters of the book
+        assert!(numbering("appendix = \"missing.md\"").check(chapters.iter()).is_err());
+        assert!(numbering("appendix = \"notes.md\"").check(chapters.iter()).is_err());
+        assert!(numbering("[prefix]\n\"missing.md\" = \"P\"").check(chapters.iter()).is_err());
+        assert!(numbering("[prefix]\n\"notes.md\" = \"P\"").check(chapters.iter()).is_ok());
+    }
+}
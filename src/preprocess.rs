@@ -1,39 +1,781 @@
-use std::fs;
 use std::collections::HashMap;
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use mdbook::book::Chapter;
+use log::warn;
+use mdbook::utils::{id_from_content, render_markdown};
 
+use crate::assets::Assets;
+use crate::cache::Cache;
 use crate::fragments;
 use crate::error::{Error, Result};
+use crate::numbering::{Numbering, Style};
+use crate::locale::Locale;
+use crate::references::{self, is_successor, Kind, Reference, References};
+use crate::theorems::{Environment, Theorems};
 
 const BLOCK_DELIM: &str = "$$";
 const INLINE_BLOCK_DELIM: &str = "$";
+/// Closes theorem-like environments and groups, a bare `$$` would be ambiguous with an equation
+const BLOCK_END: &str = "$$end";
+/// Placeholder for the URL of a deferred fragment, followed by its index
+///
+/// The private use characters keep it apart from any text of the chapter.
+const DEFERRED: &str = "\u{e000}scientific-deferred:";
+/// LaTeX commands referencing labels, which are resolved before rendering
+const REFERENCE_COMMANDS: [&str; 3] = ["\\eqref{", "\\ref{", "\\cite{"];
+/// Display math environments with several rows, which are numbered with `\label`
+const MATH_ENVIRONMENTS: [&str; 4] = ["align", "gather", "multline", "flalign"];
+/// Placeholders replaced by a list of all numbered objects of a kind
+const LISTS: [(&str, Kind); 3] = [
+    ("{{#list-of-figures}}", Kind::Figure),
+    ("{{#list-of-tables}}", Kind::Table),
+    ("{{#list-of-equations}}", Kind::Equation),
+];
 
-pub fn replace_blocks(fragment_path: &Path, asset_path: &Path, source: &str, head_num: &str, used_fragments: &mut Vec<String>, references: &mut HashMap<String, String>) -> Result<String> {
-    let mut content = String::new();
-    let mut start_line: Option<String> = None;
-    let mut figures_counter = 0;
-    let mut equations_counter = 0;
-
-    let mut add_object = move |file: String, refer: &str, title: Option<&str>| -> String {
-        used_fragments.push(file.clone());
-
-        if let Some(title) = title {
-            figures_counter += 1;
-            references.insert(refer.to_string(), format!("Figure {}{}", head_num, figures_counter));
-
-            format!("<figure id=\"{}\" class=\"figure\"><object data=\"assets/{}\" type=\"image/svg+xml\"/></object><figcaption>Figure {}{} {}</figcaption></figure>", 
-                refer, file, head_num, figures_counter, title)
-        } else if !refer.is_empty() {
-            equations_counter += 1;
-            references.insert(refer.to_string(), format!("{}{}", head_num, equations_counter));
-            format!("<div id=\"{}\" class=\"equation\"><div class=\"equation_inner\"><object data=\"assets/{}\" type=\"image/svg+xml\"></object></div><span>({}{})</span></div>\n", refer, file, head_num, equations_counter)
+/// Book-wide state of the preprocessor, shared by all chapters
+pub struct State {
+    /// Cache of rendered fragments
+    pub cache: Cache,
+    /// Publishing of fragments for the renderer
+    pub assets: Assets,
+    /// Directory searched for the content of empty blocks
+    pub asset_path: PathBuf,
+    /// Fragments used in the book, which get copied into the assets folder
+    pub used_fragments: Vec<String>,
+    /// Labels defined in the book
+    pub references: References,
+    /// Counters of numbered objects
+    pub numbering: Numbering,
+    /// Theorem-like environments
+    pub theorems: Theorems,
+    /// Strings in the language of the book
+    pub locale: Locale,
+    /// Display math referencing labels, which is rendered after all labels are known
+    pub deferred: Vec<Deferred>,
+    /// Numbered figures, tables and equations in book order
+    pub entries: Vec<Entry>,
+    /// Short list of authors and year of each bibliography entry, for author-year citations
+    pub authors: HashMap<String, (String, String)>,
+}
+
+/// Display math with its environment, or `None` for an equation
+pub struct Deferred(Option<String>, String);
+
+/// Numbered object in a list of figures, tables or equations
+pub struct Entry {
+    kind: Kind,
+    /// Displayed number, equations include their parentheses
+    number: String,
+    /// Caption of figures and tables, the chapter name for equations
+    title: String,
+    chapter: PathBuf,
+    /// Anchor of the object, empty if the object has no label
+    anchor: String,
+}
+
+/// Content of a figure
+enum Media {
+    /// Rendered fragment in the cache
+    Fragment(String),
+    /// Image given by its path relative to the chapter
+    Image(String),
+}
+
+/// Content of a block, which is added to the chapter
+enum Object<'a> {
+    /// Figure or panel of a figure group with its caption
+    Figure(Media, &'a str),
+    /// Source of a display equation and whether it is numbered when named
+    Equation(String, bool),
+    /// Source of a math environment with several rows, which may contain labels, and whether
+    /// labelled rows are numbered
+    Environment(&'a str, String, bool),
+    /// Markdown body of a table with its caption
+    Table(String, &'a str),
+    /// Start of a theorem-like environment with its identifier and optional title
+    Theorem(&'a str, &'a Environment, Option<&'a str>),
+    /// Start of a figure group with its caption
+    Group(&'a str),
+    /// Start of a group of equations, which are numbered with letters
+    Equations,
+}
+
+/// Block which contains other blocks and is closed by a bare delimiter
+enum Container {
+    /// Theorem-like environment with the line it starts in
+    Theorem(Environment, usize),
+    /// Figure group with its number, name, caption, number of panels and the line it starts in
+    Group(String, String, String, u32, usize),
+    /// Group of equations with its number, number of equations and the line it starts in
+    Equations(String, u32, usize),
+}
+
+/// Position of the brace closing an argument, which starts at the beginning of `source`
+///
+/// Nested groups like in `\tag{\text{a}}` and escaped braces are skipped.
+fn closing_brace(source: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut escaped = false;
+
+    for (pos, c) in source.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(pos),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Remove the first `\<command>{<argument>}` from LaTeX source and return its argument
+fn take_command(source: &str, command: &str) -> Result<(String, Option<String>)> {
+    let start = format!("\\{}{{", command);
+
+    match source.find(&start) {
+        Some(pos) => {
+            let rest = &source[pos + start.len()..];
+            let end = closing_brace(rest)
+                .ok_or_else(|| Error::InvalidBlock(format!("`\\{}` is not closed", command)))?;
+
+            Ok((format!("{}{}", &source[..pos], &rest[end + 1..]), Some(rest[..end].trim().to_string())))
+        },
+        None => Ok((source.to_string(), None))
+    }
+}
+
+/// Remove the tag of an equation and return it with whether it is displayed in parentheses
+///
+/// Like in LaTeX `\tag{x}` is displayed as `(x)` and `\tag*{x}` as `x`.
+fn take_tag(source: &str) -> Result<(String, Option<(String, bool)>)> {
+    match take_command(source, "tag*")? {
+        (source, Some(tag)) => Ok((source, Some((tag, false)))),
+        (source, None) => take_command(&source, "tag")
+            .map(|(source, tag)| (source, tag.map(|tag| (tag, true))))
+    }
+}
+
+/// Split the body of a math environment into rows, ignoring the rows of nested environments
+fn split_rows(body: &str) -> Vec<&str> {
+    let mut rows = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    let mut pos = 0;
+
+    while let Some(c) = body[pos..].chars().next() {
+        let rest = &body[pos..];
+
+        if rest.starts_with("\\\\") {
+            if depth == 0 {
+                rows.push(&body[start..pos]);
+                start = pos + 2;
+            }
+            pos += 2;
+        } else if rest.starts_with("\\begin{") {
+            depth += 1;
+            pos += 1;
+        } else if rest.starts_with("\\end{") {
+            depth = depth.saturating_sub(1);
+            pos += 1;
         } else {
-            format!("<div class=\"equation\"><div class=\"equation_inner\"><object data=\"assets/{}\" type=\"image/svg+xml\"></object></div></div>\n", file)
+            pos += c.len_utf8();
+        }
+    }
+    rows.push(&body[start..]);
+
+    rows
+}
+
+/// Replace `\eqref`, `\ref` and `\cite` by the numbers of their targets
+///
+/// Each reference is passed to `link` with its kind, target, anchor and text, which returns the
+/// markup inserted in its place.
+fn resolve_commands<F>(source: &str, references: &References, locale: &Locale, mut link: F) -> Result<String>
+where
+    F: FnMut(Kind, &Reference, &str, &str) -> Result<String>
+{
+    let mut out = String::new();
+    let mut rest = source;
+
+    while let Some((pos, command)) = REFERENCE_COMMANDS.iter().filter_map(|x| rest.find(x).map(|pos| (pos, *x))).min() {
+        out.push_str(&rest[..pos]);
+
+        let args = &rest[pos + command.len()..];
+        let end = closing_brace(args)
+            .ok_or_else(|| Error::InvalidReference(format!("`{}` is not closed", command.trim_end_matches('{'))))?;
+        let names = &args[..end];
+        rest = &args[end + 1..];
+
+        let lookup = |kind: Option<Kind>, name: &str| {
+            let (kind, reference) = match kind {
+                Some(kind) => references.get(kind, name).map(|x| (kind, x)),
+                None => references.find(name),
+            }.map_err(Error::InvalidReference)?;
+
+            if reference.number.is_empty() {
+                return Err(Error::InvalidReference(format!("{} `{}` is not numbered and can not be referenced", kind.name(), name)));
+            }
+
+            Ok((kind, reference))
+        };
+
+        match command {
+            "\\eqref{" => {
+                let (kind, reference) = lookup(Some(Kind::Equation), names.trim())?;
+                out.push_str(&link(kind, reference, &kind.anchor(names.trim()), &format!("({})", reference.number))?);
+            },
+            "\\ref{" => {
+                let (kind, reference) = lookup(None, names.trim())?;
+                let name = names.trim().rsplit(':').next().unwrap();
+                out.push_str(&link(kind, reference, &kind.anchor(name), &reference.number)?);
+            },
+            _ => {
+                let citations = names.split(',')
+                    .map(|name| lookup(Some(Kind::Bibliography), name.trim())
+                        .and_then(|(kind, reference)| link(kind, reference, &kind.anchor(name.trim()), &reference.number)))
+                    .collect::<Result<Vec<_>>>()?;
+
+                out.push_str(&locale.citations(&citations));
+            }
+        }
+    }
+    out.push_str(rest);
+
+    Ok(out)
+}
+
+/// Target of a reference with its URL, format and the prefix and locator of a citation
+type Target<'a> = (Kind, &'a Reference, String, Option<&'a str>, (Option<&'a str>, Option<&'a str>));
+
+/// Split a list of targets at the commas outside of brackets, like `bib:a[pp. 1, 2],b`
+fn split_targets(spec: &str) -> Vec<&str> {
+    let mut targets = Vec::new();
+    let (mut depth, mut start) = (0usize, 0);
+    for (pos, c) in spec.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                targets.push(&spec[start..pos]);
+                start = pos + 1;
+            },
+            _ => {}
+        }
+    }
+    targets.push(&spec[start..]);
+
+    targets
+}
+
+/// Split the prefix and locator from a citation like `knuth84[see][p. 42]`, a single bracket is
+/// the locator like in `knuth84[p. 42]`
+fn split_locator(target: &str) -> Result<(&str, Option<&str>, Option<&str>)> {
+    let target = target.trim();
+    let pos = match target.find('[') {
+        Some(pos) => pos,
+        None => return Ok((target, None, None)),
+    };
+
+    let args = &target[pos..];
+    if !args.ends_with(']') {
+        return Err(Error::InvalidReference(format!("unclosed bracket in citation `{}`", target)));
+    }
+
+    let args = args[1..args.len() - 1].split("][").map(|x| x.trim()).collect::<Vec<_>>();
+    let (prefix, locator) = match &args[..] {
+        [locator] => (None, *locator),
+        [prefix, locator] => (Some(*prefix), *locator),
+        _ => return Err(Error::InvalidReference(format!("citation `{}` can only have a prefix and a locator", target))),
+    };
+
+    Ok((target[..pos].trim_end(), prefix.filter(|x| !x.is_empty()), Some(locator).filter(|x| !x.is_empty())))
+}
+
+/// Format a reference to one or several targets like `fig:a,b,equ:c`
+///
+/// A target without kind has the kind of the previous one. Targets of the same kind and name are
+/// grouped, like `Figures 2, 3 and 5`, and runs of three or more consecutive numbers are collapsed
+/// into a range, like `Figures 2–4`. A single target can also be linked with custom text, like
+/// `fig:decay|the decay plot`. Citations may have a locator and a prefix, like
+/// `bib:knuth84[see][p. 42]` for `[see 3, p. 42]`, and numeric citations are compressed like
+/// `[3–5]`.
+fn format_references(spec: &str, capitalize: bool, references: &References, locale: &Locale, chapter: &Path) -> Result<String> {
+    let mut targets: Vec<Target> = Vec::new();
+    let mut last_kind = None;
+
+    // the separator of the text may be escaped in tables like `fig:decay\|text`
+    let mut elms = spec.splitn(2, '|');
+    let (spec, text) = (elms.next().unwrap().trim_end_matches('\\'), elms.next());
+
+    for target in split_targets(spec) {
+        let (target, prefix, locator) = split_locator(target)?;
+        let elms = target.split(':').collect::<Vec<_>>();
+        let (kind, name, format) = match &elms[..] {
+            [kind, name] | [kind, name, _] if Kind::from_prefix(kind).is_some() => (Kind::from_prefix(kind), *name, elms.get(2).cloned()),
+            [kind, ..] if last_kind.is_none() => return Err(Error::InvalidReference(format!("unknown reference type of `{}`", kind))),
+            [name] => (last_kind, *name, None),
+            [name, format] => (last_kind, *name, Some(*format)),
+            _ => return Err(Error::InvalidReference(format!("reference has wrong number of arguments `{}`", elms.len()))),
+        };
+        let kind = kind.unwrap();
+        last_kind = Some(kind);
+
+        if kind != Kind::Bibliography && (prefix.is_some() || locator.is_some()) {
+            return Err(Error::InvalidReference(format!("only citations can have a prefix or locator, not `{}`", target)));
+        }
+
+        if let Some(format) = format.filter(|x| *x != "title" && *x != "num") {
+            return Err(Error::InvalidReference(format!("unknown reference format `{}`", format)));
+        }
+
+        // chapters may be referenced with or without extension
+        let name = match kind {
+            Kind::Chapter if !name.ends_with(".md") => format!("{}.md", name),
+            _ => name.to_string(),
+        };
+
+        // unnumbered objects can only be referenced with custom text
+        let reference = references.get(kind, &name).map_err(Error::InvalidReference)?;
+        if reference.number.is_empty() && text.is_none() {
+            return Err(Error::InvalidReference(format!("{} `{}` is not numbered and can not be referenced", kind.name(), name)));
+        }
+
+        targets.push((kind, reference, reference.url(chapter, &kind.anchor(&name)), format, (prefix, locator)));
+    }
+
+    if let Some(text) = text {
+        return match &targets[..] {
+            [(kind, _, url, _, _)] => Ok(format!("<a class=\"{}_ref\" href='{}'>{}</a>", kind.prefix(), url, text.trim())),
+            _ => Err(Error::InvalidReference(format!("custom text `{}` can only be used with a single target", text.trim()))),
+        };
+    }
+
+    // group targets of the same kind and name, targets with only their number stand alone
+    let mut groups: Vec<Vec<Target>> = Vec::new();
+    for target in targets {
+        match groups.last_mut() {
+            Some(group) if group[0].0 == target.0 && group[0].1.name == target.1.name
+                && group[0].3 != Some("num") && target.3 != Some("num") => group.push(target),
+            _ => groups.push(vec![target]),
+        }
+    }
+
+    let groups = groups.into_iter().map(|group| {
+        let (kind, reference) = (group[0].0, group[0].1);
+        let name = match (group.len() > 1, capitalize) {
+            (plural, true) => locale.capitalized(&reference.key, &reference.name, plural),
+            (true, false) => locale.plural(&reference.key, &reference.name),
+            (false, false) => reference.name.clone(),
+        };
+
+        let number = |(_, reference, _, format, _): &Target| {
+            let number = match kind {
+                Kind::Equation => format!("({})", reference.number),
+                _ => reference.number.clone(),
+            };
+
+            match (format, &reference.title) {
+                (Some("title"), Some(title)) if *title != reference.number => format!("{} “{}”", number, title),
+                _ => number,
+            }
+        };
+        let link = |url: &str, text: String| format!("<a class=\"{}_ref\" href='{}'>{}</a>", kind.prefix(), url, text);
+
+        // only the number of a citation is linked, the prefix and locator are around it
+        let item = |target: &Target| {
+            let text = link(&target.2, number(target));
+            let text = match target.4.0 {
+                Some(prefix) => format!("{} {}", prefix, text),
+                None => text,
+            };
+
+            match target.4.1 {
+                Some(locator) => format!("{}, {}", text, locator),
+                None => text,
+            }
+        };
+
+        // runs of targets without format, and of citations only with numeric labels, form ranges
+        let plain = |target: &Target| target.3.is_none() && target.4 == (None, None);
+        let numeric = |number: &str| kind != Kind::Bibliography || number.chars().all(|x| x.is_ascii_digit());
+        let consecutive = |a: &Target, b: &Target| plain(b) && numeric(&a.1.number) && numeric(&b.1.number)
+            && is_successor(&a.1.number, &b.1.number);
+
+        // a single target is linked together with its name
+        if group.len() == 1 && group[0].4 == (None, None) {
+            let text = match (kind, name.is_empty()) {
+                _ if group[0].3 == Some("num") => reference.number.clone(),
+                (Kind::Bibliography, _) => locale.citation(&number(&group[0])),
+                (_, true) => number(&group[0]),
+                (_, false) => format!("{} {}", name, number(&group[0])),
+            };
+
+            return link(&group[0].2, text);
+        }
+
+        let mut items = Vec::new();
+        let mut start = 0;
+        while start < group.len() {
+            let mut end = start;
+            while end + 1 < group.len() && consecutive(&group[end], &group[end + 1]) {
+                end += 1;
+            }
+
+            if plain(&group[start]) && end - start >= 2 {
+                items.push(format!("{}–{}", link(&group[start].2, number(&group[start])), link(&group[end].2, number(&group[end]))));
+                start = end + 1;
+            } else {
+                items.push(item(&group[start]));
+                start += 1;
+            }
+        }
+
+        // the comma of a locator would be ambiguous between citations
+        match (kind, name.is_empty()) {
+            (Kind::Bibliography, _) if group.iter().any(|x| x.4.1.is_some()) => locale.citation(&items.join("; ")),
+            (Kind::Bibliography, _) => locale.citations(&items),
+            (_, true) => locale.enumeration(&items),
+            (_, false) => format!("{} {}", name, locale.enumeration(&items)),
+        }
+    }).collect::<Vec<_>>();
+
+    Ok(locale.enumeration(&groups))
+}
+
+/// Markup of a reference inside of LaTeX, which links to the target
+///
+/// Fragments embedded as data URLs can not link to the chapters, so references in math are an
+/// error with `assets_mode = "inline"`. TeX doubles a parameter character `#` when it writes a
+/// `\special`, so the hash of the anchor is given as `\string#`.
+fn latex_link(assets: &Assets, reference: &Reference, anchor: &str, text: &str) -> Result<String> {
+    match assets.link(&reference.chapter, anchor) {
+        Some(url) => Ok(format!("\\text{{\\special{{html:<a href=\"{}\">}}{}\\special{{html:</a>}}}}", url.replace('#', "\\string#"), text)),
+        None => Err(Error::InvalidReference(format!(
+            "reference to `{}` in math can not be linked with `assets_mode = \"inline\"`, use `assets_mode = \"src\"`", anchor
+        ))),
+    }
+}
+
+/// Return the next equation number, with a letter appended inside of a group of equations
+fn next_equation(numbering: &mut Numbering, prefix: &[String], open_blocks: &mut [Container]) -> String {
+    match open_blocks.last_mut() {
+        Some(Container::Equations(number, count, _)) => {
+            *count += 1;
+            format!("{}{}", number, Style::Alphabetic.format(*count))
+        },
+        _ => numbering.next(Kind::Equation, prefix)
+    }
+}
+
+pub fn replace_blocks(state: &mut State, ch: &Chapter) -> Result<String> {
+    let State { ref cache, ref assets, ref asset_path, ref mut used_fragments, ref mut references, ref mut numbering, ref theorems, ref mut deferred, ref locale, ref mut entries, .. } = *state;
+    let chapter = &ch.path;
+    let prefix = numbering.chapter(ch);
+
+    let mut content = String::new();
+    let mut start_line: Option<(usize, String)> = None;
+    // theorem-like environments and groups which are currently open
+    let mut open_blocks: Vec<Container> = Vec::new();
+
+    let mut add_object = move |object: Object, refer: &str, line: usize, open_blocks: &mut Vec<Container>| -> Result<String> {
+        let site = format!("{}:{}", chapter.display(), line);
+        let mut add_entry = |kind: Kind, number: String, title: &str, refer: &str| entries.push(Entry {
+            kind,
+            number,
+            title: title.to_string(),
+            chapter: chapter.to_path_buf(),
+            anchor: if refer.is_empty() { String::new() } else { kind.anchor(refer) },
+        });
+
+        // only figures can be placed in a figure group and only equations in a group of equations
+        match (open_blocks.last(), &object) {
+            (Some(Container::Group(..)), Object::Figure(..)) => {},
+            (Some(Container::Group(..)), _) => return Err(Error::InvalidBlock(format!("block in line {} is not a figure, but inside a figure group", line))),
+            (Some(Container::Equations(..)), Object::Equation(..)) | (Some(Container::Equations(..)), Object::Environment(..)) => {},
+            (Some(Container::Equations(..)), _) => return Err(Error::InvalidBlock(format!("block in line {} is not an equation, but inside a group of equations", line))),
+            _ => {}
+        }
+
+        match object {
+            Object::Figure(media, title) => {
+                let media = match media {
+                    Media::Fragment(file) => {
+                        let url = assets.url(cache, chapter, &file)?;
+                        used_fragments.push(file);
+
+                        format!("<object data=\"{}\" type=\"image/svg+xml\"></object>", url)
+                    },
+                    Media::Image(path) => format!("<img src=\"{}\" alt=\"{}\">", path, title),
+                };
+
+                // panels are labelled with letters and referenced like `<group>.<panel>`
+                if let Some(Container::Group(number, group, _, panels, _)) = open_blocks.last_mut() {
+                    *panels += 1;
+                    let letter = Style::Alphabetic.format(*panels);
+
+                    let id = if refer.is_empty() {
+                        String::new()
+                    } else {
+                        let refer = if group.is_empty() { refer.to_string() } else { format!("{}.{}", group, refer) };
+                        references.insert(Kind::Figure, &refer, Reference {
+                            name: locale.name(Kind::Figure).into(),
+                            key: Kind::Figure.key().into(),
+                            number: format!("{}{}", number, letter),
+                            title: Some(title.to_string()),
+                            chapter: chapter.to_path_buf(),
+                            site,
+                        })?;
+
+                        format!(" id=\"{}\"", Kind::Figure.anchor(&refer))
+                    };
+
+                    return Ok(format!("<figure{} class=\"subfigure\">{}<figcaption>({}) {}</figcaption></figure>", id, media, letter, title));
+                }
+
+                let number = numbering.next(Kind::Figure, &prefix);
+                add_entry(Kind::Figure, number.clone(), title, refer);
+                let id = if refer.is_empty() {
+                    String::new()
+                } else {
+                    references.insert(Kind::Figure, refer, Reference {
+                        name: locale.name(Kind::Figure).into(),
+                        key: Kind::Figure.key().into(),
+                        number: number.clone(),
+                        title: Some(title.to_string()),
+                        chapter: chapter.to_path_buf(),
+                        site,
+                    })?;
+
+                    format!(" id=\"{}\"", Kind::Figure.anchor(refer))
+                };
+
+                Ok(format!("<figure{} class=\"figure\">{}<figcaption>{} {} {}</figcaption></figure>",
+                    id, media, locale.name(Kind::Figure), number, title))
+            },
+            Object::Equation(source, numbered) => {
+                // a tag replaces the number, it is displayed next to the equation instead of by LaTeX
+                let (source, tag) = take_tag(&source)?;
+                let tag = match tag {
+                    Some(tag) => Some(tag),
+                    None if numbered && !refer.is_empty() => Some((next_equation(numbering, &prefix, open_blocks), true)),
+                    None => None
+                };
+
+                match &tag {
+                    Some((tag, true)) => add_entry(Kind::Equation, format!("({})", tag), &ch.name, refer),
+                    Some((tag, false)) => add_entry(Kind::Equation, tag.clone(), &ch.name, refer),
+                    None => {}
+                }
+
+                // references in the equation can only be resolved once all labels are known
+                let url = if REFERENCE_COMMANDS.iter().any(|x| source.contains(x)) {
+                    deferred.push(Deferred(None, source));
+                    format!("{}{}", DEFERRED, deferred.len() - 1)
+                } else {
+                    let file = fragments::parse_equation(cache, &source, 1.6)?;
+                    used_fragments.push(file.clone());
+
+                    assets.url(cache, chapter, &file)?
+                };
+
+                // unnumbered equations keep their anchor, but can not be referenced by number
+                let id = if refer.is_empty() {
+                    String::new()
+                } else {
+                    references.insert(Kind::Equation, refer, Reference {
+                        name: locale.name(Kind::Equation).into(),
+                        key: Kind::Equation.key().into(),
+                        number: tag.as_ref().map(|(tag, _)| tag.clone()).unwrap_or_default(),
+                        title: None,
+                        chapter: chapter.to_path_buf(),
+                        site,
+                    })?;
+
+                    format!(" id=\"{}\"", Kind::Equation.anchor(refer))
+                };
+
+                let number = match tag {
+                    Some((tag, true)) => format!("<span>({})</span>", tag),
+                    Some((tag, false)) => format!("<span>{}</span>", tag),
+                    None => String::new()
+                };
+
+                Ok(format!("<div{} class=\"equation\"><div class=\"equation_inner\"><object data=\"{}\" type=\"image/svg+xml\"></object></div>{}</div>\n", id, url, number))
+            },
+            Object::Environment(environment, body, numbered) => {
+                // every labelled row gets a tag with its number, which is placed by LaTeX
+                let mut anchors = String::new();
+                let mut rows = Vec::new();
+                let mut row_line = line + 1;
+                let split = split_rows(&body);
+                let count = split.len();
+
+                for (index, row) in split.into_iter().enumerate() {
+                    let (row, tag) = take_tag(row)?;
+                    let (mut row, label) = take_command(&row, "label")?;
+
+                    let tag = match (tag, &label) {
+                        (Some(tag), _) => Some(tag),
+                        (None, Some(_)) if numbered && !row.contains("\\nonumber") && !row.contains("\\notag") =>
+                            Some((next_equation(numbering, &prefix, open_blocks), true)),
+                        _ => None
+                    };
+
+                    match &tag {
+                        Some((tag, true)) => add_entry(Kind::Equation, format!("({})", tag), &ch.name, label.as_deref().unwrap_or("")),
+                        Some((tag, false)) => add_entry(Kind::Equation, tag.clone(), &ch.name, label.as_deref().unwrap_or("")),
+                        None => {}
+                    }
+
+                    if let Some(label) = label {
+                        references.insert(Kind::Equation, &label, Reference {
+                            name: locale.name(Kind::Equation).into(),
+                            key: Kind::Equation.key().into(),
+                            number: tag.as_ref().map(|(tag, _)| tag.clone()).unwrap_or_default(),
+                            title: None,
+                            chapter: chapter.to_path_buf(),
+                            site: format!("{}:{}", chapter.display(), row_line),
+                        })?;
+
+                        // the anchor is placed at the height of its row, assuming rows of equal height
+                        anchors.push_str(&format!("<span id=\"{}\" style=\"top: {}%\"></span>", Kind::Equation.anchor(&label), 100 * index / count));
+                    }
+
+                    match tag {
+                        Some((tag, true)) => row.push_str(&format!("\\tag{{{}}}", tag)),
+                        Some((tag, false)) => row.push_str(&format!("\\tag*{{{}}}", tag)),
+                        None => {}
+                    }
+
+                    row_line += row.matches('\n').count();
+                    rows.push(row);
+                }
+
+                // the starred environment only numbers rows with a tag
+                let (environment, source) = (format!("{}*", environment), rows.join("\\\\"));
+                let url = if REFERENCE_COMMANDS.iter().any(|x| source.contains(x)) {
+                    deferred.push(Deferred(Some(environment), source));
+                    format!("{}{}", DEFERRED, deferred.len() - 1)
+                } else {
+                    let file = fragments::parse_environment(cache, &environment, &source, 1.6)?;
+                    used_fragments.push(file.clone());
+
+                    assets.url(cache, chapter, &file)?
+                };
+
+                Ok(format!("<div class=\"equation\">{}<div class=\"equation_inner\"><object data=\"{}\" type=\"image/svg+xml\"></object></div></div>\n", anchors, url))
+            },
+            Object::Table(body, title) => {
+                let number = numbering.next(Kind::Table, &prefix);
+                add_entry(Kind::Table, number.clone(), title, refer);
+                let id = if refer.is_empty() {
+                    String::new()
+                } else {
+                    references.insert(Kind::Table, refer, Reference {
+                        name: locale.name(Kind::Table).into(),
+                        key: Kind::Table.key().into(),
+                        number: number.clone(),
+                        title: Some(title.to_string()),
+                        chapter: chapter.to_path_buf(),
+                        site,
+                    })?;
+
+                    format!(" id=\"{}\"", Kind::Table.anchor(refer))
+                };
+
+                // the empty lines end the HTML blocks, so that the body is parsed as Markdown
+                Ok(format!("<figure{} class=\"table\"><figcaption>{} {} {}</figcaption>\n\n{}\n</figure>\n",
+                    id, locale.name(Kind::Table), number, title, body))
+            },
+            Object::Theorem(key, env, title) => {
+                open_blocks.push(Container::Theorem(env.clone(), line));
+
+                let number = env.counter.as_ref()
+                    .map(|counter| numbering.next_in(Kind::Theorem, counter, &prefix))
+                    .unwrap_or_default();
+
+                let mut head = env.name.clone();
+                if !number.is_empty() {
+                    head = format!("{} {}", head, number);
+                }
+
+                let id = if refer.is_empty() {
+                    String::new()
+                } else {
+                    references.insert(Kind::Theorem, refer, Reference {
+                        name: env.name.clone(),
+                        key: key.to_string(),
+                        number,
+                        title: title.map(|x| x.to_string()),
+                        chapter: chapter.to_path_buf(),
+                        site,
+                    })?;
+
+                    format!(" id=\"{}\"", Kind::Theorem.anchor(refer))
+                };
+
+                if let Some(title) = title {
+                    head = format!("{} ({})", head, title);
+                }
+
+                Ok(format!("<div{} class=\"theorem theorem-{}\"><p class=\"theorem_head\">{}.</p>\n", id, key, head))
+            },
+            Object::Group(title) => {
+                let number = numbering.next(Kind::Figure, &prefix);
+                add_entry(Kind::Figure, number.clone(), title, refer);
+
+                let id = if refer.is_empty() {
+                    String::new()
+                } else {
+                    references.insert(Kind::Figure, refer, Reference {
+                        name: locale.name(Kind::Figure).into(),
+                        key: Kind::Figure.key().into(),
+                        number: number.clone(),
+                        title: Some(title.to_string()),
+                        chapter: chapter.to_path_buf(),
+                        site,
+                    })?;
+
+                    format!(" id=\"{}\"", Kind::Figure.anchor(refer))
+                };
+
+                open_blocks.push(Container::Group(number, refer.to_string(), title.to_string(), 0, line));
+
+                Ok(format!("<figure{} class=\"figure figure_group\"><div class=\"subfigures\">", id))
+            },
+            Object::Equations => {
+                let number = numbering.next(Kind::Equation, &prefix);
+
+                let id = if refer.is_empty() {
+                    String::new()
+                } else {
+                    references.insert(Kind::Equation, refer, Reference {
+                        name: locale.name(Kind::Equation).into(),
+                        key: Kind::Equation.key().into(),
+                        number: number.clone(),
+                        title: None,
+                        chapter: chapter.to_path_buf(),
+                        site,
+                    })?;
+
+                    format!(" id=\"{}\"", Kind::Equation.anchor(refer))
+                };
+
+                open_blocks.push(Container::Equations(number, 0, line));
+
+                Ok(format!("<div{} class=\"equation_group\">", id))
+            },
         }
     };
 
-    source.split("\n")
-    .filter_map(|line| {
+    let res = ch.content.split("\n")
+    .enumerate()
+    .filter_map(|(line_num, line)| {
         let line = line.trim();
 
         if !line.starts_with(BLOCK_DELIM) {
@@ -46,11 +788,11 @@ pub fn replace_blocks(fragment_path: &Path, asset_path: &Path, source: &str, hea
             }
         } else if line.ends_with(BLOCK_DELIM) && line.len() > 3{
             // line starts and end with BLOCK_DELIM, set content to empty
-            start_line = Some(line.to_string());
+            start_line = Some((line_num + 1, line.to_string()));
             content = "".into();
         }
 
-        if let Some(param) = start_line.take() {
+        if let Some((block_line, param)) = start_line.take() {
             let elms = param.splitn(3, ",")
                 .map(|x| x.trim())
                 .map(|x| x.replace(BLOCK_DELIM, ""))
@@ -60,105 +802,754 @@ pub fn replace_blocks(fragment_path: &Path, asset_path: &Path, source: &str, hea
 
             // if there is no content, try to load it from file
             if content.is_empty() {
-                let path = asset_path.join(elms[1]).with_extension("tex");
+                let extension = if elms[0] == "table" { "md" } else { "tex" };
+                let path = asset_path.join(elms[1]).with_extension(extension);
                 if path.exists() {
                     content = fs::read_to_string(path).unwrap();
                 } else {
-                    eprintln!("Block empty, but file `{}` was not found!", elms[1]);
+                    warn!("Block empty, but file `{}` was not found!", elms[1]);
                     return None;
                 }
             }
 
             let generated_out = match &elms[..] {
                 ["latex", refer, title] => {
-                    fragments::parse_latex(fragment_path, &content)
-                        .map(|file| add_object(file, refer, Some(title)))
+                    fragments::parse_latex(cache, &content)
+                        .and_then(|file| add_object(Object::Figure(Media::Fragment(file), title), refer, block_line, &mut open_blocks))
                 },
                 ["gnuplot", refer, title] => {
-                    fragments::parse_gnuplot(fragment_path, &content)
-                        .map(|file| add_object(file, refer, Some(title)))
+                    fragments::parse_gnuplot(cache, &content)
+                        .and_then(|file| add_object(Object::Figure(Media::Fragment(file), title), refer, block_line, &mut open_blocks))
                 },
                 ["gnuplotonly", refer, title] => {
-                    fragments::parse_gnuplot_only(fragment_path, &content)
-                        .map(|file| add_object(file, refer, Some(title)))
+                    fragments::parse_gnuplot_only(cache, &content)
+                        .and_then(|file| add_object(Object::Figure(Media::Fragment(file), title), refer, block_line, &mut open_blocks))
+                },
+                ["image", refer, title] => {
+                    add_object(Object::Figure(Media::Image(content.trim().to_string()), title), refer, block_line, &mut open_blocks)
+                },
+                ["table", refer, title] => {
+                    add_object(Object::Table(content.clone(), title), refer, block_line, &mut open_blocks)
+                },
+
+                // the starred forms are not numbered, like in LaTeX
+                [environment] if MATH_ENVIRONMENTS.contains(&environment.trim_end_matches('*')) => {
+                    let object = Object::Environment(environment.trim_end_matches('*'), content.clone(), !environment.ends_with('*'));
+                    add_object(object, "", block_line, &mut open_blocks)
                 },
 
                 ["equation", refer] | ["equ", refer] => {
-                    fragments::parse_equation(fragment_path, &content, 1.6)
-                        .map(|file| add_object(file, refer, None))
+                    add_object(Object::Equation(content.clone(), true), refer, block_line, &mut open_blocks)
                 }
 
-                ["equation"] | ["equ"] | _ => {
-                    fragments::parse_equation(fragment_path, &content, 1.6)
-                        .map(|file| add_object(file, "", None))
+                ["equation*", refer] | ["equ*", refer] => {
+                    add_object(Object::Equation(content.clone(), false), refer, block_line, &mut open_blocks)
+                }
+
+                _ => {
+                    add_object(Object::Equation(content.clone(), false), "", block_line, &mut open_blocks)
                 },
             };
             content = "".into();
 
             Some(generated_out)
+        } else if line == BLOCK_END {
+            // closes the innermost container
+            match open_blocks.pop() {
+                None => Some(Err(Error::InvalidBlock(format!("`{}` in line {} closes no environment or group", BLOCK_END, line_num + 1)))),
+                Some(Container::Theorem(env, _)) if env.qed => Some(Ok("\n<p class=\"qed\">∎</p>\n</div>\n".into())),
+                Some(Container::Theorem(..)) => Some(Ok("\n</div>\n".into())),
+                Some(Container::Group(number, _, title, _, _)) => {
+                    Some(Ok(format!("</div><figcaption>{} {} {}</figcaption></figure>\n", locale.name(Kind::Figure), number, title)))
+                },
+                Some(Container::Equations(..)) => Some(Ok("</div>\n".into())),
+            }
         } else {
-            start_line = Some(line.to_string());
+            let elms = line[BLOCK_DELIM.len()..].splitn(3, ",")
+                .map(|x| x.trim())
+                .collect::<Vec<_>>();
+            let refer = elms.get(1).cloned().unwrap_or("");
+
+            // theorem-like environments and groups keep their body, which may contain other blocks
+            if let Some(env) = theorems.get(elms[0]) {
+                return Some(add_object(Object::Theorem(elms[0], env, elms.get(2).cloned()), refer, line_num + 1, &mut open_blocks));
+            } else if elms[0] == "figures" {
+                return Some(add_object(Object::Group(elms.get(2).cloned().unwrap_or("")), refer, line_num + 1, &mut open_blocks));
+            } else if elms[0] == "subequations" {
+                return Some(add_object(Object::Equations, refer, line_num + 1, &mut open_blocks));
+            }
+
+            start_line = Some((line_num + 1, line.to_string()));
             None
         }
     })
-    .collect::<Result<Vec<_>>>()
-    .map(|x| x.join("\n"))
+    .collect::<Result<Vec<_>>>();
+
+    match open_blocks.last() {
+        Some(Container::Theorem(env, line)) => Err(Error::InvalidBlock(format!("{} started in line {} is never closed with `{}`", env.name, line, BLOCK_END))),
+        Some(Container::Group(_, _, _, _, line)) => Err(Error::InvalidBlock(format!("figure group started in line {} is never closed with `{}`", line, BLOCK_END))),
+        Some(Container::Equations(_, _, line)) => Err(Error::InvalidBlock(format!("group of equations started in line {} is never closed with `{}`", line, BLOCK_END))),
+        None => res.map(|x| x.join("\n"))
+    }
 }
 
-pub fn replace_inline_blocks(fragment_path: &Path, source: &str, references: &HashMap<String, String>, used_fragments: &mut Vec<String>) -> Result<String> {
-    source.split("\n").enumerate().map(|(line_num, line)| {
-        if line.matches(INLINE_BLOCK_DELIM).count() % 2 != 0 {
-            return Err(Error::UnevenNumberDollar);
+/// Level and text of an ATX heading like `## Title ##`
+fn atx_heading(line: &str) -> Option<(usize, &str)> {
+    let line = line.trim();
+    let title = line.trim_start_matches('#');
+    let level = line.len() - title.len();
+    if level == 0 || level > 6 || !(title.is_empty() || title.starts_with(' ')) {
+        return None;
+    }
+
+    // a closing sequence has to be separated by a space, like in `# C# #`
+    let title = title.trim();
+    let stripped = title.trim_end_matches('#');
+    match stripped.is_empty() || stripped.ends_with(' ') {
+        true => Some((level, stripped.trim_end())),
+        false => Some((level, title)),
+    }
+}
+
+/// Level of a setext heading from the line underlining it
+fn setext_level(line: &str) -> Option<usize> {
+    let line = line.trim();
+    match line.chars().next() {
+        Some('=') if line.chars().all(|x| x == '=') => Some(1),
+        Some('-') if line.chars().all(|x| x == '-') => Some(2),
+        _ => None,
+    }
+}
+
+/// Id which mdBook generates for a heading, from the heading rendered to HTML
+///
+/// Inline math is left out, as it is only rendered later. Headings with math get this id
+/// explicitly once the math is replaced.
+fn heading_id(title: &str) -> String {
+    let text = title.split(INLINE_BLOCK_DELIM).step_by(2).collect::<String>();
+    let html = render_markdown(&format!("# {}", text), false);
+
+    id_from_content(html.trim().trim_start_matches("<h1>").trim_end_matches("</h1>"))
+}
+
+/// Register a chapter and all of its headings as reference targets
+///
+/// Chapters are referenced by their path, headings by the id generated by the renderer. As
+/// heading ids are only unique within a chapter, they can also be qualified with the chapter path
+/// like `part2/models.md#energy`.
+pub fn collect_sections(state: &mut State, ch: &Chapter) -> Result<()> {
+    let State { ref mut references, ref mut numbering, ref theorems, ref locale, .. } = *state;
+    let chapter = ch.path.to_string_lossy().replace('\\', "/");
+    let number = numbering.chapter(ch).join(".");
+
+    let kind = match (number.is_empty(), ch.number.as_ref().map(|x| x.0.len())) {
+        (true, _) => None,
+        (false, Some(depth)) if depth > 1 => Some(Kind::Section),
+        (false, _) => Some(Kind::Chapter),
+    };
+
+    references.insert(Kind::Chapter, &chapter, Reference {
+        name: kind.map(|x| locale.name(x)).unwrap_or_default().into(),
+        key: kind.map(|x| x.key()).unwrap_or_default().into(),
+        number: if number.is_empty() { ch.name.clone() } else { number.clone() },
+        title: Some(ch.name.clone()),
+        chapter: ch.path.clone(),
+        site: chapter.clone(),
+    })?;
+
+    // the renderer makes ids unique within a chapter by appending a counter
+    let mut id_counter = HashMap::new();
+    let mut in_code = false;
+    let mut in_block = false;
+    // counters of the section levels below the chapter
+    let mut sections: Vec<u32> = Vec::new();
+    // a single line paragraph, which becomes a setext heading when underlined
+    let mut paragraph = None;
+    let mut last_blank = true;
+
+    for (line_num, line) in ch.content.lines().enumerate() {
+        let line = line.trim();
+        let candidate = paragraph.take();
+
+        // skip code and blocks, for example comments in gnuplot
+        if line.starts_with("```") || line.starts_with("~~~") {
+            in_code = !in_code;
+        } else if line.starts_with(BLOCK_DELIM) && !(line.ends_with(BLOCK_DELIM) && line.len() > 3) {
+            // the body of theorem-like environments and groups is Markdown and may contain headings
+            let kind = line[BLOCK_DELIM.len()..].split(',').next().unwrap().trim();
+
+            if in_block {
+                in_block = false;
+            } else if line != BLOCK_END && theorems.get(kind).is_none() && kind != "figures" && kind != "subequations" {
+                in_block = true;
+            }
         }
 
-        line.split(INLINE_BLOCK_DELIM).enumerate().map(|(i, elm)| {
-            if i % 2 == 0 {
-                return Ok(elm.to_string());
+        if in_code || in_block || line.starts_with(BLOCK_DELIM) || line.starts_with("```") || line.starts_with("~~~") {
+            last_blank = false;
+            continue;
+        }
+
+        let (level, title, line_num) = match (atx_heading(line), setext_level(line), candidate) {
+            (Some((level, title)), _, _) => (level, title, line_num),
+            (None, Some(level), Some((title, line_num))) => (level, title, line_num),
+            _ => {
+                // lists, quotes, tables and HTML are not underlined as headings
+                let plain = !line.is_empty() && !line.starts_with(|x: char| "|>*+-<".contains(x))
+                    && !line.trim_start_matches(|x: char| x.is_ascii_digit()).starts_with(". ");
+                if plain && last_blank {
+                    paragraph = Some((line, line_num));
+                }
+
+                last_blank = line.is_empty();
+                continue;
             }
+        };
+        last_blank = false;
+
+        // headings with math get their id explicitly, which mdBook does not count
+        let raw_id = heading_id(title);
+        let id = match title.contains(INLINE_BLOCK_DELIM) {
+            true => raw_id,
+            false => {
+                let count = id_counter.entry(raw_id.clone()).or_insert(0);
+                let id = match *count {
+                    0 => raw_id,
+                    other => format!("{}-{}", raw_id, other),
+                };
+                *count += 1;
+
+                id
+            }
+        };
+
+        // the top-level heading is the chapter itself, lower levels are numbered like `3.1.2`
+        sections.resize(level.max(1) - 1, 0);
+        if let Some(last) = sections.last_mut() {
+            *last += 1;
+        }
+        let section = Some(number.clone()).into_iter()
+            .chain(sections.iter().map(|x| x.to_string()))
+            .collect::<Vec<_>>()
+            .join(".");
 
-            let generated_out = if elm.starts_with("ref:") {
-                let elms = elm.split(":").skip(1).collect::<Vec<&str>>();
+        let reference = || Reference {
+            name: if number.is_empty() { String::new() } else { locale.name(Kind::Section).into() },
+            key: Kind::Section.key().into(),
+            number: if number.is_empty() { title.to_string() } else { section.clone() },
+            title: Some(title.to_string()),
+            chapter: ch.path.clone(),
+            site: format!("{}:{}", chapter, line_num + 1),
+        };
 
-                // we expect a type and reference name
-                if elms.len() != 2 {
-                    return Ok(elm.to_string());
+        references.insert(Kind::Section, &format!("{}#{}", chapter, id), reference())?;
+        references.insert_or_ambiguous(Kind::Section, &id, reference())?;
+    }
+
+    Ok(())
+}
+
+/// Format an author-year citation like `p:knuth84,lamport94`, similar to the commands of natbib
+///
+/// The form `p` gives `(Knuth, 1984; Lamport, 1994)`, `t` gives `Knuth (1984)`, `author` only
+/// the authors and `year` only the year. Each citation links to its entry in the bibliography.
+/// Keys of the forms `p` and `t` may have a prefix and locator, like `p:knuth84[see][p. 42]`.
+fn format_citation(spec: &str, references: &References, authors: &HashMap<String, (String, String)>, locale: &Locale, chapter: &Path) -> Result<String> {
+    let mut elms = spec.splitn(2, ':');
+    let (form, keys) = match (elms.next(), elms.next()) {
+        (Some(form), Some(keys)) => (form, keys),
+        _ => return Err(Error::InvalidReference(format!("citation `{}` has to be like `cite:p:<key>`", spec))),
+    };
+
+    let citations = split_targets(keys).into_iter().map(|key| {
+        let (key, prefix, locator) = split_locator(key)?;
+        let reference = references.get(Kind::Bibliography, key).map_err(Error::InvalidReference)?;
+        let (names, year) = &authors[key];
+
+        let prefix = prefix.map(|x| format!("{} ", x)).unwrap_or_default();
+        let locator = locator.map(|x| format!(", {}", x)).unwrap_or_default();
+        let text = match form {
+            "p" => format!("{}{}, {}{}", prefix, names, year, locator),
+            "t" => format!("{} ({}{}{})", names, prefix, year, locator),
+            "author" | "year" if !prefix.is_empty() || !locator.is_empty() =>
+                return Err(Error::InvalidReference(format!("citation form `{}` can not have a prefix or locator", form))),
+            "author" => names.clone(),
+            "year" => year.clone(),
+            _ => return Err(Error::InvalidReference(format!("unknown citation form `{}`, expected `p`, `t`, `author` or `year`", form))),
+        };
+
+        Ok(format!("<a class=\"bib_ref\" href='{}'>{}</a>", reference.url(chapter, &Kind::Bibliography.anchor(key)), text))
+    }).collect::<Result<Vec<_>>>()?;
+
+    Ok(match form {
+        "p" => format!("({})", citations.join("; ")),
+        _ => locale.enumeration(&citations),
+    })
+}
+
+/// Whether a line is a caption or an entry in a list of figures, whose text may contain LaTeX references
+fn is_caption(line: &str) -> bool {
+    line.contains("<figcaption>") || line.starts_with("<li class=\"list_entry\">")
+}
+
+/// Return the keys cited in a chapter by `$ref:bib:..$`, `$cite:..$` and `\cite`, in the order of the text
+///
+/// This runs after the blocks are replaced, so citations in display math are found in the
+/// deferred source of the math. Like `replace_inline_blocks`, `\cite` outside of math is only
+/// found in captions.
+pub fn collect_citations(state: &State, ch: &Chapter) -> Vec<String> {
+    let mut keys = Vec::new();
+    let cite = |source: &str, keys: &mut Vec<String>| {
+        for (pos, _) in source.match_indices("\\cite{") {
+            let args = &source[pos + "\\cite{".len()..];
+            let args = &args[..args.find('}').unwrap_or(0)];
+            keys.extend(args.split(',').map(|x| x.trim().to_string()).filter(|x| !x.is_empty()));
+        }
+    };
+
+    for line in ch.content.lines() {
+        for (i, elm) in line.split(INLINE_BLOCK_DELIM).enumerate() {
+            if i % 2 == 0 {
+                if is_caption(line) {
+                    cite(elm, &mut keys);
                 }
+                continue;
+            }
 
-                match &elms[..] {
-                    ["fig", refere] => {
-                        references.get::<str>(refere)
-                            .ok_or(Error::InvalidReference(format!("could not find reference to `{}` in line {}", elms[1], line_num)))
-                            .map(|x| format!("<a class=\"fig_ref\" href='#{}'>{}</a>", elms[1], x))
-                    },
-                    ["bib", refere] => {
-                        references.get::<str>(refere)
-                            .ok_or(Error::InvalidReference(format!("could not find reference to `{}` in line {}", elms[1], line_num)))
-                            .map(|x| format!("<a class=\"bib_ref\" href='bibliography.html#{}'>{}</a>", elms[1], x))
-                    },
-                    ["equ", refere] => {
-                        references.get::<str>(refere)
-                            .ok_or(Error::InvalidReference(format!("could not find reference to `{}` in line {}", elms[1], line_num)))
-                            .map(|x| format!("<a class=\"equ_ref\" href='#{}'>Eq. ({})</a>", elms[1], x))
-                    },
-                    [kind, _] => Err(Error::InvalidReference(format!("unknown reference type of `{}` in line {}", kind, line_num))),
-                    _ =>         Err(Error::InvalidReference(format!("reference has wrong number of arguments `{}` in line {}", elms.len(), line_num)))
+            if elm.starts_with("cite:") {
+                let spec = elm.splitn(3, ':').nth(2).unwrap_or("");
+                keys.extend(split_targets(spec).into_iter().map(|x| x.split('[').next().unwrap().trim().to_string()));
+                continue;
+            }
+
+            if !(elm.starts_with("ref:") || elm.starts_with("Ref:")) {
+                cite(elm, &mut keys);
+                continue;
+            }
+
+            // targets without kind have the kind of the previous one, like in `format_references`
+            let spec = elm[4..].split('|').next().unwrap().trim_end_matches('\\');
+            let mut last_kind = None;
+            for target in split_targets(spec) {
+                let target = target.split('[').next().unwrap();
+                let elms = target.trim().split(':').collect::<Vec<_>>();
+                let (kind, name) = match Kind::from_prefix(elms[0]) {
+                    Some(kind) if elms.len() > 1 => (Some(kind), elms[1]),
+                    _ => (last_kind, elms[0]),
+                };
+                last_kind = kind;
 
+                if kind == Some(Kind::Bibliography) {
+                    keys.push(name.to_string());
                 }
+            }
+        }
+
+        for (pos, _) in line.match_indices(DEFERRED) {
+            let index = line[pos + DEFERRED.len()..].chars().take_while(|x| x.is_ascii_digit()).collect::<String>();
+            if let Some(Deferred(_, source)) = index.parse::<usize>().ok().and_then(|x| state.deferred.get(x)) {
+                cite(source, &mut keys);
+            }
+        }
+    }
+
+    keys
+}
+
+/// Render the list of all numbered objects of a kind, with links relative to `chapter`
+///
+/// Each entry is on its own line, so that math in captions is rendered like in the text.
+fn list_of(kind: Kind, entries: &[Entry], locale: &Locale, chapter: &Path) -> String {
+    let items = entries.iter()
+        .filter(|entry| entry.kind == kind)
+        .map(|entry| format!("<li class=\"list_entry\"><a href='{}'>{} {}</a> {}</li>",
+            references::url(chapter, &entry.chapter, &entry.anchor), locale.name(kind), entry.number, entry.title))
+        .collect::<Vec<_>>();
+
+    format!("<ul class=\"list_of list_of_{}\">\n{}\n</ul>", kind.prefix(), items.join("\n"))
+}
+
+pub fn replace_inline_blocks(state: &mut State, ch: &Chapter) -> Result<String> {
+    let State { ref cache, ref assets, ref mut used_fragments, ref references, ref deferred, ref locale, ref entries, ref authors, .. } = *state;
+    let chapter = &ch.path;
+
+    // placeholders on a line of their own are replaced by the lists of figures, tables and equations
+    let content = ch.content.split("\n")
+        .map(|line| match LISTS.iter().find(|(placeholder, _)| line.trim() == *placeholder) {
+            Some((_, kind)) => list_of(*kind, entries, locale, chapter),
+            None => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut in_code = false;
+    content.split("\n").enumerate().map(|(line_num, line)| {
+        if line.matches(INLINE_BLOCK_DELIM).count() % 2 != 0 {
+            return Err(Error::UnevenNumberDollar);
+        }
+
+        // mdBook would derive the id of a heading from the rendered math, so it is given explicitly
+        if line.trim().starts_with("```") || line.trim().starts_with("~~~") {
+            in_code = !in_code;
+        }
+        let heading = atx_heading(line)
+            .filter(|(_, title)| !in_code && title.contains(INLINE_BLOCK_DELIM))
+            .map(|(level, title)| (level, heading_id(title)));
+
+        let in_line = |err| match err {
+            Error::InvalidReference(err) => Error::InvalidReference(format!("{} in line {}", err, line_num)),
+            err => err
+        };
+
+        // render display math with references, now that all labels are known
+        let mut line = line.to_string();
+        while let Some(pos) = line.find(DEFERRED) {
+            let index = line[pos + DEFERRED.len()..].chars().take_while(|x| x.is_ascii_digit()).collect::<String>();
+            let Deferred(environment, source) = index.parse::<usize>().ok()
+                .and_then(|index| deferred.get(index))
+                .ok_or_else(|| Error::InvalidBlock(format!("invalid placeholder of deferred math in line {}", line_num)))?;
+
+            let source = resolve_commands(source, references, locale, |_, reference, anchor, text| latex_link(assets, reference, anchor, text))
+                .map_err(in_line)?;
+            let file = match environment {
+                Some(environment) => fragments::parse_environment(cache, environment, &source, 1.6)?,
+                None => fragments::parse_equation(cache, &source, 1.6)?,
+            };
+
+            line.replace_range(pos..pos + DEFERRED.len() + index.len(), &assets.url(cache, chapter, &file)?);
+            used_fragments.push(file);
+        }
+
+        line.split(INLINE_BLOCK_DELIM).enumerate().map(|(i, elm)| {
+            // captions may reference labels like LaTeX does
+            if i % 2 == 0 && is_caption(&line) {
+                return resolve_commands(elm, references, locale, |kind, reference, anchor, text| {
+                    Ok(format!("<a class=\"{}_ref\" href='{}'>{}</a>", kind.prefix(), reference.url(chapter, anchor), text))
+                }).map_err(in_line);
+            } else if i % 2 == 0 {
+                return Ok(elm.to_string());
+            }
+
+            // `$Ref:..$` starts a sentence
+            let generated_out = if elm.starts_with("ref:") || elm.starts_with("Ref:") {
+                format_references(&elm[4..], elm.starts_with('R'), references, locale, chapter).map_err(in_line)
+            } else if let Some(spec) = elm.strip_prefix("cite:") {
+                format_citation(spec, references, authors, locale, chapter).map_err(in_line)
             } else {
-                fragments::parse_equation(fragment_path, elm, 1.3)
-                    .map(|filename| {
-                        let res = format!("<object class=\"equation_inline\" data=\"assets/{}\" type=\"image/svg+xml\"></object>", filename);
+                resolve_commands(elm, references, locale, |_, reference, anchor, text| latex_link(assets, reference, anchor, text))
+                    .map_err(in_line)
+                    .and_then(|elm| fragments::parse_equation(cache, &elm, 1.3))
+                    .and_then(|filename| {
+                        let res = format!("<object class=\"equation_inline\" data=\"{}\" type=\"image/svg+xml\"></object>", assets.url(cache, chapter, &filename)?);
                         used_fragments.push(filename);
 
-                        res
+                        Ok(res)
                     })
             };
 
             generated_out
         })
         .collect::<Result<Vec<String>>>()
-        .map(|x| x.join("\n"))
+        // keep the line intact, for example a row of a table
+        .map(|x| x.join(""))
+        .map(|line| match (heading, atx_heading(&line)) {
+            (Some((level, id)), Some((_, title))) => {
+                let title = render_markdown(title, false);
+                let title = title.trim().trim_start_matches("<p>").trim_end_matches("</p>");
+
+                // the HTML block of the heading has to end before the next paragraph
+                format!("<h{0} id=\"{1}\"><a class=\"header\" href=\"#{1}\">{2}</a></h{0}>\n", level, id, title)
+            },
+            _ => line,
+        })
     })
     .collect::<Result<Vec<_>>>()
     .map(|x| x.join("\n"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use mdbook::book::SectionNumber;
+
+    /// State of an English book with the default numbering, the cache is in `dir`
+    fn state(dir: &Path) -> State {
+        let locale = Locale::from_config(None, None, &[]).unwrap();
+
+        State {
+            cache: Cache::open(dir, None).unwrap(),
+            assets: Assets::Src("assets".into()),
+            asset_path: dir.to_path_buf(),
+            used_fragments: Vec::new(),
+            references: References::new(),
+            numbering: Numbering::from_config(None).unwrap(),
+            theorems: Theorems::from_config(None, &locale).unwrap(),
+            locale,
+            deferred: Vec::new(),
+            entries: Vec::new(),
+            authors: HashMap::new(),
+        }
+    }
+
+    /// Replace the blocks of a first chapter `intro.md`
+    fn replace(state: &mut State, content: &str) -> Result<String> {
+        let mut ch = Chapter::new("Intro", content.into(), "intro.md", Vec::new());
+        ch.number = Some(SectionNumber(vec![1]));
+
+        collect_sections(state, &ch).and_then(|_| replace_blocks(state, &ch))
+    }
+
+/// Book with the figures `a` to `d`, the table `t` and the bibliography entries `k1` to `k4`
+    fn book(dir: &Path) -> State {
+        let mut state = state(dir);
+        replace(&mut state, "$$image, a, Alpha\na.png\n$$\n\n$$image, b, Beta\nb.png\n$$\n\n\
+            $$image, c, Gamma\nc.png\n$$\n\n$$image, d, Delta\nd.png\n$$\n\n$$table, t, Runs\n| a |\n|---|\n$$\n").unwrap();
+
+        let entries = [("k1", "Knuth", "1984"), ("k2", "Knuth and Lamport", "1994"), ("k3", "Lamport", "1986"), ("k4", "Wirth", "1976")];
+        for (i, (key, names, year)) in entries.iter().enumerate() {
+            state.references.insert(Kind::Bibliography, key, Reference {
+                name: String::new(),
+                key: String::new(),
+                number: (i + 1).to_string(),
+                title: None,
+                chapter: PathBuf::from("bibliography.md"),
+                site: "refs.bib".into(),
+            }).unwrap();
+            state.authors.insert(key.to_string(), (names.to_string(), year.to_string()));
+        }
+
+        state
+    }
+
+    /// Remove the tags of links from generated markup
+    fn strip_links(html: &str) -> String {
+        html.split('<').map(|x| x.split_once('>').map(|x| x.1).unwrap_or(x)).collect()
+    }
+
+    /// Format a reference in `intro.md` and remove the links from the result
+    fn reference(state: &State, spec: &str, capitalize: bool) -> Result<String> {
+        format_references(spec, capitalize, &state.references, &state.locale, Path::new("intro.md")).map(|x| strip_links(&x))
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("mdbook-scientific-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+
+        path
+    }
+
+    #[test]
+    fn latex_links() {
+        let reference = Reference {
+            name: "Eq.".into(),
+            key: "equation".into(),
+            number: "1".into(),
+            title: None,
+            chapter: PathBuf::from("intro.md"),
+            site: "intro.md:1".into(),
+        };
+
+        let link = latex_link(&Assets::Src("assets".into()), &reference, "equ-x", "(1)").unwrap();
+        assert_eq!(link, "\\text{\\special{html:<a href=\"../intro.html\\string#equ-x\">}(1)\\special{html:</a>}}");
+        assert!(latex_link(&Assets::Inline, &reference, "equ-x", "(1)").is_err());
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(take_command("a = b \\label{x}", "label").unwrap(), ("a = b ".into(), Some("x".into())));
+        assert_eq!(take_command("a = b", "label").unwrap(), ("a = b".into(), None));
+        assert_eq!(take_command("a \\label{eq:{x}} = b", "label").unwrap(), ("a  = b".into(), Some("eq:{x}".into())));
+        assert_eq!(take_command("a \\tag{\\text{a} \\}} b", "tag").unwrap(), ("a  b".into(), Some("\\text{a} \\}".into())));
+        assert!(take_command("a \\label{x", "label").is_err());
+    }
+
+    #[test]
+    fn tags() {
+        assert_eq!(take_tag("a \\tag{\\text{a}}").unwrap(), ("a ".into(), Some(("\\text{a}".into(), true))));
+        assert_eq!(take_tag("a \\tag*{x}").unwrap(), ("a ".into(), Some(("x".into(), false))));
+        assert_eq!(take_tag("a").unwrap(), ("a".into(), None));
+    }
+
+    #[test]
+    fn rows() {
+        assert_eq!(split_rows("a \\\\ b"), ["a ", " b"]);
+        assert_eq!(split_rows("a \\\\ \\begin{cases} 1 \\\\ 2 \\end{cases} \\\\ c"), ["a ", " \\begin{cases} 1 \\\\ 2 \\end{cases} ", " c"]);
+    }
+
+    #[test]
+    fn headings() {
+        assert_eq!(atx_heading("## Energy models"), Some((2, "Energy models")));
+        assert_eq!(atx_heading("# Closed #"), Some((1, "Closed")));
+        assert_eq!(atx_heading("# C#"), Some((1, "C#")));
+        assert_eq!(atx_heading("#hashtag"), None);
+        assert_eq!(atx_heading("####### Too deep"), None);
+
+        assert_eq!(setext_level("====="), Some(1));
+        assert_eq!(setext_level("---"), Some(2));
+        assert_eq!(setext_level("-- -"), None);
+    }
+
+    #[test]
+    fn heading_ids() {
+        assert_eq!(heading_id("Energy models"), "energy-models");
+        assert_eq!(heading_id("The `run` method"), "the-run-method");
+        assert_eq!(heading_id("*Fast* and **safe**"), "fast-and-safe");
+        assert_eq!(heading_id("Energy $E = mc^2$"), "energy");
+    }
+
+    #[test]
+    fn unnamed_objects() {
+        let dir = temp_dir("unnamed");
+        let mut state = state(&dir);
+
+        let html = replace(&mut state, "$$image, , First\na.png\n$$\n\n$$image, , Second\nb.png\n$$\n\n$$table, , Runs\n| a |\n|---|\n$$\n").unwrap();
+        assert!(html.contains("<figure class=\"figure\"><img src=\"a.png\" alt=\"First\"><figcaption>Figure 1.1 First</figcaption></figure>"), "{}", html);
+        assert!(html.contains("<figcaption>Figure 1.2 Second</figcaption>"), "{}", html);
+        assert!(html.contains("<figure class=\"table\"><figcaption>Table 1.1 Runs</figcaption>"), "{}", html);
+        assert!(state.references.find("fig:").is_err());
+
+        drop(state);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn citations() {
+        let dir = temp_dir("citations");
+        let mut state = state(&dir);
+
+        let content = "Prose \\cite{zzz} and $cite:p:c, d[p. 2]$.\n\n$$table, t, Runs from \\cite{b}\n| a |\n|---|\n$$\n\nSee $ref:bib:e, f$ and $Ref:tab:t$.\n";
+        let mut ch = Chapter::new("Intro", replace(&mut state, content).unwrap(), "intro.md", Vec::new());
+        ch.number = Some(SectionNumber(vec![1]));
+
+        assert_eq!(collect_citations(&state, &ch), ["c", "d", "b", "e", "f"]);
+
+        drop(state);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn targets() {
+        assert_eq!(split_targets("fig:a"), ["fig:a"]);
+        assert_eq!(split_targets("fig:a,b, tab:t"), ["fig:a", "b", " tab:t"]);
+        assert_eq!(split_targets("bib:k1[pp. 1, 2],k2"), ["bib:k1[pp. 1, 2]", "k2"]);
+    }
+
+    #[test]
+    fn references() {
+        let dir = temp_dir("references");
+        let state = book(&dir);
+        let text = |spec| reference(&state, spec, false).unwrap();
+
+        assert_eq!(format_references("fig:a", false, &state.references, &state.locale, Path::new("intro.md")).unwrap(),
+            "<a class=\"fig_ref\" href='#fig-a'>Figure 1.1</a>");
+        assert_eq!(text("fig:a,b"), "Figures 1.1 and 1.2");
+        assert_eq!(text("fig:a,c"), "Figures 1.1 and 1.3");
+        assert_eq!(text("fig:a,b,c,d"), "Figures 1.1–1.4");
+        assert_eq!(text("fig:a,b,d"), "Figures 1.1, 1.2 and 1.4");
+        assert_eq!(text("fig:a, tab:t, fig:b"), "Figure 1.1, Table 1.1 and Figure 1.2");
+        assert_eq!(text("tab:t"), "Table 1.1");
+        assert_eq!(text("bib:k1,k2,k3"), "[1–3]");
+        assert_eq!(text("bib:k1,k3"), "[1, 3]");
+
+        assert!(reference(&state, "a", false).is_err());
+        assert!(reference(&state, "fig:x", false).is_err());
+        assert!(reference(&state, "tab:a", false).is_err());
+
+        drop(state);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn capitalized_references() {
+        let dir = temp_dir("capitalized");
+        let mut state = state(&dir);
+        let cfg = "figure = 'fig.'\nfigure_plural = 'figs.'\nfigure_start = 'figure'".parse::<toml::Value>().unwrap();
+        state.locale = Locale::from_config(None, Some(&cfg), &[]).unwrap();
+        replace(&mut state, "$$image, a, Alpha\na.png\n$$\n\n$$image, b, Beta\nb.png\n$$\n").unwrap();
+
+        assert_eq!(reference(&state, "fig:a", false).unwrap(), "fig. 1.1");
+        assert_eq!(reference(&state, "fig:a", true).unwrap(), "Figure 1.1");
+        assert_eq!(reference(&state, "fig:a,b", false).unwrap(), "figs. 1.1 and 1.2");
+        assert_eq!(reference(&state, "fig:a,b", true).unwrap(), "Figs. 1.1 and 1.2");
+
+        drop(state);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reference_forms() {
+        let dir = temp_dir("forms");
+        let state = book(&dir);
+        let text = |spec| reference(&state, spec, false).unwrap();
+
+        assert_eq!(format_references("fig:a|the plot", false, &state.references, &state.locale, Path::new("intro.md")).unwrap(),
+            "<a class=\"fig_ref\" href='#fig-a'>the plot</a>");
+        assert_eq!(text("fig:a\\| the plot "), "the plot");
+        assert_eq!(text("fig:a:num"), "1.1");
+        assert_eq!(text("fig:a:num,b"), "1.1 and Figure 1.2");
+        assert_eq!(text("fig:a:title"), "Figure 1.1 “Alpha”");
+        assert_eq!(text("fig:a:title,b,c"), "Figures 1.1 “Alpha”, 1.2 and 1.3");
+        assert_eq!(text("tab:t:title"), "Table 1.1 “Runs”");
+
+        assert!(reference(&state, "fig:a,b|the plots", false).is_err());
+        assert!(reference(&state, "fig:a:name", false).is_err());
+
+        drop(state);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn author_year_citations() {
+        let dir = temp_dir("author-year");
+        let state = book(&dir);
+        let cite = |spec| format_citation(spec, &state.references, &state.authors, &state.locale, Path::new("intro.md"));
+        let text = |spec| cite(spec).map(|x| strip_links(&x));
+
+        assert_eq!(cite("p:k1").unwrap(), "(<a class=\"bib_ref\" href='bibliography.html#k1'>Knuth, 1984</a>)");
+        assert_eq!(text("p:k1,k2").unwrap(), "(Knuth, 1984; Knuth and Lamport, 1994)");
+        assert_eq!(text("t:k1").unwrap(), "Knuth (1984)");
+        assert_eq!(text("t:k1,k3").unwrap(), "Knuth (1984) and Lamport (1986)");
+        assert_eq!(text("author:k2").unwrap(), "Knuth and Lamport");
+        assert_eq!(text("year:k4").unwrap(), "1976");
+
+        assert!(cite("k1").is_err());
+        assert!(cite("q:k1").is_err());
+        assert!(cite("p:k9").is_err());
+
+        drop(state);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn locators() {
+        assert_eq!(split_locator("k1").unwrap(), ("k1", None, None));
+        assert_eq!(split_locator(" k1 [p. 2] ").unwrap(), ("k1", None, Some("p. 2")));
+        assert_eq!(split_locator("k1[see][p. 2]").unwrap(), ("k1", Some("see"), Some("p. 2")));
+        assert_eq!(split_locator("k1[see][]").unwrap(), ("k1", Some("see"), None));
+        assert!(split_locator("k1[p. 2").is_err());
+        assert!(split_locator("k1[a][b][c]").is_err());
+
+        let dir = temp_dir("locators");
+        let state = book(&dir);
+        let text = |spec| reference(&state, spec, false).unwrap();
+        let cite = |spec| format_citation(spec, &state.references, &state.authors, &state.locale, Path::new("intro.md")).map(|x| strip_links(&x));
+
+        assert_eq!(text("bib:k1[p. 2]"), "[1, p. 2]");
+        assert_eq!(text("bib:k1[see][p. 2]"), "[see 1, p. 2]");
+        assert_eq!(text("bib:k1[see][]"), "[see 1]");
+        assert_eq!(text("bib:k1[p. 2],k2,k3,k4"), "[1, p. 2; 2–4]");
+        assert_eq!(text("bib:k1,k2,k3[ch. 4]"), "[1; 2; 3, ch. 4]");
+        assert!(reference(&state, "fig:a[p. 2]", false).is_err());
+
+        assert_eq!(cite("p:k1[see][p. 42]").unwrap(), "(see Knuth, 1984, p. 42)");
+        assert_eq!(cite("p:k1[p. 42],k2").unwrap(), "(Knuth, 1984, p. 42; Knuth and Lamport, 1994)");
+        assert_eq!(cite("t:k1[p. 42]").unwrap(), "Knuth (1984, p. 42)");
+        assert_eq!(cite("t:k1[e.g.][]").unwrap(), "Knuth (e.g. 1984)");
+        assert!(cite("author:k1[p. 42]").is_err());
+        assert!(cite("year:k1[see][]").is_err());
+
+        drop(state);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use mdbook::utils::fs::path_to_root;
+
+use crate::error::{Error, Result};
+
+/// Kind of a labeled object, each kind has its own namespace of labels
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Figure,
+    Equation,
+    Table,
+    Theorem,
+    Section,
+    Chapter,
+    Bibliography,
+}
+
+impl Kind {
+    /// Parse the kind of a reference like `$ref:fig:name$`
+    pub fn from_prefix(prefix: &str) -> Option<Kind> {
+        match prefix {
+            "fig" => Some(Kind::Figure),
+            "equ" => Some(Kind::Equation),
+            "tab" => Some(Kind::Table),
+            "thm" => Some(Kind::Theorem),
+            "sec" => Some(Kind::Section),
+            "ch" => Some(Kind::Chapter),
+            "bib" => Some(Kind::Bibliography),
+            _ => None
+        }
+    }
+
+    /// Prefix used in references of this kind
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Kind::Figure => "fig",
+            Kind::Equation => "equ",
+            Kind::Table => "tab",
+            Kind::Theorem => "thm",
+            Kind::Section => "sec",
+            Kind::Chapter => "ch",
+            Kind::Bibliography => "bib",
+        }
+    }
+
+    /// HTML id of a labeled object, prefixed so that labels of different kinds can't collide
+    ///
+    /// Bibliography entries and headings keep their key, as their anchors are generated by the
+    /// bibliography and by the renderer. Chapters are linked without an anchor.
+    pub fn anchor(&self, name: &str) -> String {
+        match self {
+            Kind::Bibliography => name.to_string(),
+            Kind::Section => name.rsplit('#').next().unwrap_or(name).to_string(),
+            Kind::Chapter => String::new(),
+            _ => format!("{}-{}", self.prefix(), name),
+        }
+    }
+
+    /// Human readable name used in error messages
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Figure => "figure",
+            Kind::Equation => "equation",
+            Kind::Table => "table",
+            Kind::Theorem => "theorem",
+            Kind::Section => "section",
+            Kind::Chapter => "chapter",
+            Kind::Bibliography => "bibliography entry",
+        }
+    }
+
+    /// Key of the displayed name in the locale, empty for objects without a name
+    pub fn key(&self) -> &'static str {
+        match self {
+            Kind::Figure => "figure",
+            Kind::Equation => "equation",
+            Kind::Table => "table",
+            Kind::Theorem => "theorem",
+            Kind::Section => "section",
+            Kind::Chapter => "chapter",
+            Kind::Bibliography => "",
+        }
+    }
+}
+
+/// A labeled object which can be referenced in the text
+pub struct Reference {
+    /// Name displayed in front of the number, for example `Figure` or `Lemma`
+    pub name: String,
+    /// Key of the name in the locale, which also gives its plural, for example `figure` or `lemma`
+    pub key: String,
+    /// Number of the object without its name, for example `1.2`
+    pub number: String,
+    /// Caption or title of the object, if it has one
+    pub title: Option<String>,
+    /// Chapter defining the label, relative to the source directory
+    pub chapter: PathBuf,
+    /// Location of the definition, used in error messages
+    pub site: String,
+}
+
+impl Reference {
+    /// Text displayed for a reference to this object, for example `Figure 1.2`
+    pub fn text(&self) -> String {
+        if self.name.is_empty() {
+            self.number.clone()
+        } else {
+            format!("{} {}", self.name, self.number)
+        }
+    }
+
+    /// URL of the anchor in the defining chapter, relative to the chapter `from`, see [`url`]
+    pub fn url(&self, from: &Path, anchor: &str) -> String {
+        url(from, &self.chapter, anchor)
+    }
+}
+
+/// URL of an anchor in `chapter`, relative to the chapter `from`
+///
+/// Without an anchor the URL points to the chapter itself.
+pub fn url(from: &Path, chapter: &Path, anchor: &str) -> String {
+    if anchor.is_empty() {
+        format!("{}{}", path_to_root(from), chapter_url(chapter))
+    } else if chapter == from {
+        format!("#{}", anchor)
+    } else {
+        format!("{}{}#{}", path_to_root(from), chapter_url(chapter), anchor)
+    }
+}
+
+/// Whether the number `b` directly follows `a`, like `1.2` and `1.3` or `3a` and `3b`
+pub fn is_successor(a: &str, b: &str) -> bool {
+    let (head_a, tail_a) = a.split_at(a.trim_end_matches(|c: char| c.is_ascii_digit()).len());
+    let (head_b, tail_b) = b.split_at(b.trim_end_matches(|c: char| c.is_ascii_digit()).len());
+
+    if !tail_a.is_empty() && !tail_b.is_empty() {
+        return head_a == head_b && tail_a.parse::<u64>().ok().map(|x| x + 1) == tail_b.parse().ok();
+    }
+
+    // sub-numbered objects end with a letter
+    match (a.chars().last(), b.chars().last()) {
+        (Some(x), Some(y)) if x.is_ascii_lowercase() && y.is_ascii_lowercase() => {
+            a[..a.len() - 1] == b[..b.len() - 1] && x as u8 + 1 == y as u8
+        },
+        _ => false
+    }
+}
+
+/// All labels of the book with the object they are referring to
+#[derive(Default)]
+pub struct References {
+    labels: HashMap<(Kind, String), Reference>,
+    /// Label of each displayed text, to detect objects which can't be told apart
+    displayed: HashMap<(Kind, String), String>,
+    /// Labels defined more than once, which can only be used in a qualified form
+    ambiguous: HashMap<(Kind, String), Vec<String>>,
+}
+
+impl References {
+    pub fn new() -> References {
+        References::default()
+    }
+
+    /// Define a new label, fails if the label is already defined for this kind
+    pub fn insert(&mut self, kind: Kind, name: &str, reference: Reference) -> Result<()> {
+        if let Some(existing) = self.labels.get(&(kind, name.to_string())) {
+            return Err(Error::DuplicateLabel(format!(
+                "{} `{}` is defined twice, in {} and in {}",
+                kind.name(), name, existing.site, reference.site
+            )));
+        }
+
+        // different objects with the same number make references ambiguous, except for headings
+        // which share the number of their chapter and unnumbered objects
+        match self.displayed.get(&(kind, reference.text())) {
+            _ if kind == Kind::Section || reference.number.is_empty() => {},
+            Some(other) => warn!(
+                "{} `{}` in {} and `{}` in {} are both displayed as `{}`",
+                kind.name(), name, reference.site, other, self.labels[&(kind, other.clone())].site, reference.text()
+            ),
+            None => {
+                self.displayed.insert((kind, reference.text()), name.to_string());
+            }
+        }
+
+        self.labels.insert((kind, name.to_string()), reference);
+
+        Ok(())
+    }
+
+    /// Define a label which may be defined more than once, like the id of a heading
+    ///
+    /// A label defined more than once can't be referenced and an error lists all definitions.
+    pub fn insert_or_ambiguous(&mut self, kind: Kind, name: &str, reference: Reference) -> Result<()> {
+        let key = (kind, name.to_string());
+
+        if let Some(sites) = self.ambiguous.get_mut(&key) {
+            sites.push(reference.site);
+        } else if let Some(existing) = self.labels.remove(&key) {
+            self.ambiguous.insert(key, vec![existing.site, reference.site]);
+        } else {
+            self.insert(kind, name, reference)?;
+        }
+
+        Ok(())
+    }
+
+    /// Look up a label of the given kind
+    ///
+    /// If the label only exists for another kind, the error names the kind of the labeled object.
+    pub fn get(&self, kind: Kind, name: &str) -> std::result::Result<&Reference, String> {
+        if let Some(reference) = self.labels.get(&(kind, name.to_string())) {
+            return Ok(reference);
+        }
+
+        if let Some(sites) = self.ambiguous.get(&(kind, name.to_string())) {
+            return Err(format!(
+                "{} `{}` is ambiguous, it is defined in {}; qualify it with the chapter like `{}:<chapter>#{}`",
+                kind.name(), name, sites.join(", "), kind.prefix(), name
+            ));
+        }
+
+        match self.labels.iter().find(|((_, x), _)| x == name) {
+            Some(((other, _), reference)) => Err(format!(
+                "`{}:{}` does not exist, but {} `{}` is defined in {}, use `{}:{}`",
+                kind.prefix(), name, other.name(), name, reference.site, other.prefix(), name
+            )),
+            None => Err(format!("could not find reference to {} `{}`", kind.name(), name)),
+        }
+    }
+
+    /// Look up a label of any kind except bibliography entries, like `\ref` in LaTeX
+    ///
+    /// The label may be prefixed with its kind like `fig:decay`, otherwise it has to be unique
+    /// among all kinds.
+    pub fn find(&self, name: &str) -> std::result::Result<(Kind, &Reference), String> {
+        let mut elms = name.splitn(2, ':');
+        if let (Some(kind), Some(name)) = (elms.next().and_then(Kind::from_prefix), elms.next()) {
+            let name = match kind {
+                Kind::Chapter if !name.ends_with(".md") => format!("{}.md", name),
+                _ => name.to_string(),
+            };
+
+            return self.get(kind, &name).map(|reference| (kind, reference));
+        }
+
+        let mut found = self.labels.iter()
+            .filter(|((kind, x), _)| x == name && *kind != Kind::Bibliography)
+            .map(|((kind, _), reference)| (*kind, reference))
+            .collect::<Vec<_>>();
+
+        match found.len() {
+            0 => Err(format!("could not find reference to `{}`", name)),
+            1 => Ok(found.remove(0)),
+            _ => Err(format!(
+                "`{}` is defined for several kinds ({}), prefix it with the kind like `{}:{}`",
+                name, found.iter().map(|(kind, _)| kind.name()).collect::<Vec<_>>().join(", "), found[0].0.prefix(), name
+            )),
+        }
+    }
+}
+
+/// URL of the rendered chapter, relative to the book root
+pub fn chapter_url(chapter: &Path) -> String {
+    chapter.with_extension("html")
+        .to_string_lossy()
+        .replace('\\', "/")
+}
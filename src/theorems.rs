@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use crate::error::{Error, Result};
+use crate::locale::Locale;
+
+/// A theorem-like environment, for example a lemma or a proof
+#[derive(Clone, Debug)]
+pub struct Environment {
+    /// Name displayed in the head and in references, for example `Lemma`
+    pub name: String,
+    /// Counter used for numbering, environments with the same counter are numbered together
+    pub counter: Option<String>,
+    /// Whether the end is marked with ∎
+    pub qed: bool,
+}
+
+/// All theorem-like environments, which are keyed by the identifier used in blocks
+pub struct Theorems {
+    environments: HashMap<String, Environment>,
+}
+
+impl Theorems {
+    /// Default environments with their names in the language of the book
+    fn new(locale: &Locale) -> Theorems {
+        let env = |key: &str, counter: Option<&str>, qed| Environment {
+            name: locale.get(key).unwrap_or(key).to_string(),
+            counter: counter.map(|x| x.to_string()),
+            qed,
+        };
+
+        let environments = vec![
+            ("definition", env("definition", Some("definition"), false)),
+            ("theorem", env("theorem", Some("theorem"), false)),
+            ("lemma", env("lemma", Some("theorem"), false)),
+            ("corollary", env("corollary", Some("theorem"), false)),
+            ("proof", env("proof", None, true)),
+        ];
+
+        Theorems {
+            environments: environments.into_iter().map(|(key, env)| (key.to_string(), env)).collect(),
+        }
+    }
+
+    /// Parse the `theorems` table, which adds environments or changes the default ones
+    ///
+    /// ```toml
+    /// [preprocessor.scientific.theorems]
+    /// proposition = { name = "Proposition", counter = "theorem" }
+    /// remark = { name = "Remark", numbered = false }
+    /// ```
+    ///
+    /// The name of an environment defaults to the string of its identifier in the locale.
+    pub fn from_config(cfg: Option<&toml::Value>, locale: &Locale) -> Result<Theorems> {
+        let mut theorems = Theorems::new(locale);
+
+        let cfg = match cfg {
+            Some(cfg) => cfg.as_table()
+                .ok_or_else(|| Error::InvalidConfig("`theorems` has to be a table".into()))?,
+            None => return Ok(theorems)
+        };
+
+        for (key, val) in cfg {
+            let val = val.as_table()
+                .ok_or_else(|| Error::InvalidConfig(format!("`theorems.{}` has to be a table", key)))?;
+
+            let mut env = theorems.environments.remove(key).unwrap_or_else(|| Environment {
+                name: locale.get(key).unwrap_or(key).to_string(),
+                counter: Some(key.clone()),
+                qed: false,
+            });
+
+            if let Some(name) = val.get("name") {
+                env.name = name.as_str()
+                    .ok_or_else(|| Error::InvalidConfig(format!("`theorems.{}.name` has to be a string", key)))?
+                    .to_string();
+            }
+
+            if let Some(counter) = val.get("counter") {
+                env.counter = Some(counter.as_str()
+                    .ok_or_else(|| Error::InvalidConfig(format!("`theorems.{}.counter` has to be a string", key)))?
+                    .to_string());
+            }
+
+            match val.get("numbered").map(|x| x.as_bool()) {
+                Some(Some(false)) => env.counter = None,
+                Some(Some(true)) if env.counter.is_none() => env.counter = Some(key.clone()),
+                Some(None) => return Err(Error::InvalidConfig(format!("`theorems.{}.numbered` has to be a boolean", key))),
+                _ => {}
+            }
+
+            if let Some(qed) = val.get("qed") {
+                env.qed = qed.as_bool()
+                    .ok_or_else(|| Error::InvalidConfig(format!("`theorems.{}.qed` has to be a boolean", key)))?;
+            }
+
+            theorems.environments.insert(key.clone(), env);
+        }
+
+        Ok(theorems)
+    }
+
+    /// Look up the environment of a block identifier
+    pub fn get(&self, key: &str) -> Option<&Environment> {
+        self.environments.get(key)
+    }
+}